@@ -1,3 +1,6 @@
 fn main() {
-    tonic_build::compile_protos("proto/orderbook.proto").unwrap();
+    tonic_build::configure()
+        .type_attribute(".", "#[derive(serde::Serialize)]")
+        .compile(&["proto/orderbook.proto"], &["proto"])
+        .unwrap();
 }
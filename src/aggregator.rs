@@ -1,77 +1,317 @@
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
-    convert,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    convert, slice,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Context;
-use log::{debug, info};
-use ordered_float::NotNan;
-use tokio::sync::{mpsc::Receiver, watch::Sender};
+use log::{debug, info, warn};
+use rust_decimal::{Decimal, RoundingStrategy};
+use tokio::sync::{broadcast, mpsc::Receiver, watch::Sender};
 
-use crate::orderbook::{self, Summary};
+use crate::arbitrage::ArbitrageDetector;
+use crate::configuration::{FeeConfig, ScaleConfig, SyntheticConfig};
+use crate::decimal::{format_scaled, to_f64};
+use crate::orderbook::{
+    self, BestBidOffer, ExchangeBestBidOffer, LevelSource, Opportunity, Summary,
+};
+use crate::synthetic::SyntheticBooks;
+use crate::trades::{Trade, TradeStats};
+
+#[derive(Debug, Clone, Default)]
+pub struct AggregatorConfig {
+    /// Symbol of the aggregated book. Orders for other symbols are only used as synthetic legs.
+    pub symbol: String,
+    pub max_levels: usize,
+    /// Also publish levels merged by price in `consolidated_bids`/`consolidated_asks`.
+    pub consolidate: bool,
+    /// Merge levels by their price after paying the exchange taker fee instead of the quoted one.
+    pub fee_adjusted: bool,
+    /// Fees by exchange name. Exchanges without an entry are assumed to charge no fees.
+    pub fees: HashMap<String, FeeConfig>,
+    /// Legs used to imply a synthetic book on each exchange, merged with the direct one.
+    pub synthetic: Option<SyntheticConfig>,
+    pub scale: ScaleConfig,
+    /// Window of the rolling trade volume published in the summary.
+    pub trade_volume_window: Duration,
+}
 
 #[derive(Debug)]
-pub(crate) struct ExchangeOrders {
+pub struct ExchangeOrders {
     pub exchange_name: String,
+    pub symbol: String,
     pub asks: Vec<Level>,
     pub bids: Vec<Level>,
 }
 
-#[derive(Debug, Ord, PartialOrd, PartialEq, Eq)]
-pub(crate) struct Level {
-    pub price: NotNan<f64>,
-    pub amount: NotNan<f64>,
+#[derive(Debug, Clone, Ord, PartialOrd, PartialEq, Eq)]
+pub struct Level {
+    pub price: Decimal,
+    pub amount: Decimal,
     pub exchange_name: String,
+    pub source: LevelSource,
 }
 
-pub(crate) async fn orders_aggregator(
+pub async fn orders_aggregator(
     mut receiver: Receiver<ExchangeOrders>,
+    mut trades: Receiver<Trade>,
     sender: Sender<Summary>,
-    max_levels: usize,
+    bbo_sender: Sender<BestBidOffer>,
+    opportunities: broadcast::Sender<Opportunity>,
+    trades_sender: broadcast::Sender<orderbook::Trade>,
+    config: AggregatorConfig,
 ) -> anyhow::Result<()> {
     let mut exchanges = HashMap::new();
-    while let Some(exchange_orders) = receiver.recv().await {
+    let mut synthetic_books = config.synthetic.clone().map(|synthetic| {
+        SyntheticBooks::new(
+            config.symbol.clone(),
+            synthetic,
+            config.max_levels,
+            config.scale,
+        )
+    });
+    let mut arbitrage_detector = ArbitrageDetector::new();
+    let mut trade_stats = TradeStats::new(config.trade_volume_window);
+    loop {
+        let exchange_orders = tokio::select! {
+            Some(trade) = trades.recv() => {
+                if trade.symbol == config.symbol {
+                    trade_stats.update(&trade);
+                    // Sending only fails when no one is subscribed to trades, which is fine.
+                    let _ = trades_sender.send(trade.to_proto(&config.scale));
+                }
+                continue;
+            }
+            exchange_orders = receiver.recv() => match exchange_orders {
+                Some(exchange_orders) => exchange_orders,
+                None => break,
+            },
+        };
         debug!(
-            "received orders: exchange:{} len: {}",
+            "received orders: exchange:{} symbol:{} len: {}",
             exchange_orders.exchange_name,
+            exchange_orders.symbol,
             exchange_orders.asks.len()
         );
-        exchanges.insert(exchange_orders.exchange_name.clone(), exchange_orders);
-        let summary = sort_orders_and_calculate_spread(&exchanges, max_levels);
-        if sender.send(summary).context("sending summary").is_err() {
+        if exchange_orders.symbol == config.symbol {
+            exchanges.insert(exchange_orders.exchange_name.clone(), exchange_orders);
+        } else {
+            let symbol = exchange_orders.symbol.clone();
+            let is_leg = synthetic_books
+                .as_mut()
+                .is_some_and(|books| books.update(exchange_orders));
+            if !is_leg {
+                warn!("ignoring orders for symbol {}", symbol);
+                continue;
+            }
+        }
+        let books = || {
+            exchanges
+                .values()
+                .chain(synthetic_books.iter().flat_map(SyntheticBooks::books))
+        };
+        let (mut summary, bbo) = if config.fee_adjusted {
+            let adjusted_books: Vec<_> = books()
+                .map(|orders| {
+                    orders.fee_adjusted(&config.fees_for(&orders.exchange_name), config.scale.price)
+                })
+                .collect();
+            (
+                sort_orders_and_calculate_spread(&adjusted_books, &config),
+                best_bid_offer(&adjusted_books, &config.scale),
+            )
+        } else {
+            (
+                sort_orders_and_calculate_spread(books(), &config),
+                best_bid_offer(books(), &config.scale),
+            )
+        };
+        bbo_sender.send_if_modified(|current| {
+            let modified = *current != bbo;
+            if modified {
+                *current = bbo;
+            }
+            modified
+        });
+        trade_stats.fill_summary(&mut summary, SystemTime::now(), &config.scale);
+        if sender.is_closed() {
             info!("sender channel closed. Exiting");
             break;
         }
+        // Subscribers are only woken up when the summary changed.
+        sender.send_if_modified(|current| {
+            let modified = *current != summary;
+            if modified {
+                *current = summary;
+            }
+            modified
+        });
+        for opportunity in arbitrage_detector.update(&exchanges, &config, SystemTime::now()) {
+            // Sending only fails when no one is subscribed to opportunities, which is fine.
+            let _ = opportunities.send(opportunity);
+        }
     }
     Ok(())
 }
 
-fn sort_orders_and_calculate_spread(
-    exchanges: &HashMap<String, ExchangeOrders>,
-    max_levels: usize,
+impl AggregatorConfig {
+    pub fn fees_for(&self, exchange_name: &str) -> FeeConfig {
+        self.fees.get(exchange_name).copied().unwrap_or_default()
+    }
+}
+
+impl ExchangeOrders {
+    /// Returns the orders with the taker fee applied: bids pay out less and asks cost more.
+    /// Synthetic levels pay the fee on both of their legs. Adjusted prices are rounded to
+    /// `price_scale` decimal places, down for bids and up for asks.
+    pub fn fee_adjusted(&self, fees: &FeeConfig, price_scale: u32) -> ExchangeOrders {
+        let taker_fee = fees.taker_fee_decimal();
+        let adjust = |levels: &[Level],
+                      factor: Decimal,
+                      other_leg_factor: Decimal,
+                      rounding: RoundingStrategy| {
+            levels
+                .iter()
+                .map(|level| {
+                    let factor = match level.source {
+                        LevelSource::Direct => factor,
+                        LevelSource::Synthetic => factor / other_leg_factor,
+                    };
+                    Level {
+                        price: (level.price * factor).round_dp_with_strategy(price_scale, rounding),
+                        ..level.clone()
+                    }
+                })
+                .collect()
+        };
+        ExchangeOrders {
+            exchange_name: self.exchange_name.clone(),
+            symbol: self.symbol.clone(),
+            asks: adjust(
+                &self.asks,
+                Decimal::ONE + taker_fee,
+                Decimal::ONE - taker_fee,
+                RoundingStrategy::AwayFromZero,
+            ),
+            bids: adjust(
+                &self.bids,
+                Decimal::ONE - taker_fee,
+                Decimal::ONE + taker_fee,
+                RoundingStrategy::ToZero,
+            ),
+        }
+    }
+}
+
+/// Best bid and ask of each exchange and across exchanges. Only the first level of each book is
+/// looked at, as books are sorted.
+fn best_bid_offer<'a>(
+    exchanges: impl IntoIterator<Item = &'a ExchangeOrders>,
+    scale: &ScaleConfig,
+) -> BestBidOffer {
+    let mut by_exchange: BTreeMap<&str, (Option<&Level>, Option<&Level>)> = BTreeMap::new();
+    for orders in exchanges {
+        let (best_bid, best_ask) = by_exchange.entry(&orders.exchange_name).or_default();
+        if let Some(bid) = orders.bids.first() {
+            if best_bid.is_none_or(|best| bid.price > best.price) {
+                *best_bid = Some(bid);
+            }
+        }
+        if let Some(ask) = orders.asks.first() {
+            if best_ask.is_none_or(|best| ask.price < best.price) {
+                *best_ask = Some(ask);
+            }
+        }
+    }
+    let best_bid = by_exchange
+        .values()
+        .filter_map(|(bid, _)| *bid)
+        .max_by_key(|bid| bid.price);
+    let best_ask = by_exchange
+        .values()
+        .filter_map(|(_, ask)| *ask)
+        .min_by_key(|ask| ask.price);
+    BestBidOffer {
+        best_bid: best_bid.map(|bid| bid.to_proto(scale)),
+        best_ask: best_ask.map(|ask| ask.to_proto(scale)),
+        exchanges: by_exchange
+            .into_iter()
+            .map(|(exchange, (bid, ask))| ExchangeBestBidOffer {
+                exchange: exchange.to_owned(),
+                best_bid: bid.map(|bid| bid.to_proto(scale)),
+                best_ask: ask.map(|ask| ask.to_proto(scale)),
+            })
+            .collect(),
+    }
+}
+
+fn sort_orders_and_calculate_spread<'a>(
+    exchanges: impl IntoIterator<Item = &'a ExchangeOrders>,
+    config: &AggregatorConfig,
 ) -> Summary {
+    let max_levels = config.max_levels;
+    let scale = &config.scale;
     let (all_bids, all_asks): (Vec<_>, Vec<_>) = exchanges
-        .values()
+        .into_iter()
         .map(|exchange| (exchange.bids.as_slice(), exchange.asks.as_slice()))
         .unzip();
+    let (consolidated_bids, consolidated_asks) = if config.consolidate {
+        let to_proto = |levels: Vec<PriceLevel>| -> Vec<orderbook::ConsolidatedLevel> {
+            levels.iter().map(|level| level.to_proto(scale)).collect()
+        };
+        (
+            to_proto(consolidate_levels(
+                merge_sorted(&all_bids, convert::identity),
+                max_levels,
+            )),
+            to_proto(consolidate_levels(
+                merge_sorted(&all_asks, Reverse).map(|ask| ask.0),
+                max_levels,
+            )),
+        )
+    } else {
+        (vec![], vec![])
+    };
+    let truncated =
+        |levels: &[&[Level]]| levels.iter().map(|l| l.len()).sum::<usize>() > max_levels;
+    let (bids_truncated, asks_truncated) = (truncated(&all_bids), truncated(&all_asks));
     let sorted_bids = sort_merged(&all_bids, max_levels, convert::identity);
     let sorted_asks = sort_merged(&all_asks, max_levels, Reverse);
-    let sorted_bids: Vec<orderbook::Level> = sorted_bids.into_iter().map(Into::into).collect();
+    let spread = sorted_asks.first().map_or(Decimal::ZERO, |ask| ask.0.price)
+        - sorted_bids.first().map_or(Decimal::ZERO, |bid| bid.price);
+    let sorted_bids: Vec<orderbook::Level> = sorted_bids
+        .into_iter()
+        .map(|bid| bid.to_proto(scale))
+        .collect();
     let sorted_asks: Vec<orderbook::Level> = sorted_asks
         .into_iter()
-        .map(|rev_ask| rev_ask.0.into())
+        .map(|rev_ask| rev_ask.0.to_proto(scale))
         .collect();
-    let spread = sorted_asks.first().map_or(0.0, |ask| ask.price)
-        - sorted_bids.first().map_or(0.0, |ask| ask.price);
     Summary {
         bids: sorted_bids,
         asks: sorted_asks,
-        spread,
+        spread: to_f64(spread),
+        consolidated_bids,
+        consolidated_asks,
+        spread_decimal: format_scaled(spread, scale.price),
+        price_scale: scale.price,
+        quantity_scale: scale.quantity,
+        bids_truncated,
+        asks_truncated,
+        ..Default::default()
     }
 }
 
 fn sort_merged<'a, T, F, U>(levels: &[&'a [T]], max_levels: usize, f: F) -> Vec<U>
+where
+    T: Ord + 'a,
+    F: Fn(&'a T) -> U,
+    U: Ord + 'a,
+{
+    merge_sorted(levels, f).take(max_levels).collect()
+}
+
+fn merge_sorted<'a, T, F, U>(levels: &[&'a [T]], f: F) -> MergeSorted<'a, T, F, U>
 where
     T: Ord + 'a,
     F: Fn(&'a T) -> U,
@@ -84,25 +324,111 @@ where
             heap.push((f(item), i))
         }
     }
-    let mut result = Vec::with_capacity(max_levels);
-    while let Some((item, i)) = heap.pop() {
-        result.push(item);
-        if result.len() == max_levels {
-            break;
+    MergeSorted { heap, iterators, f }
+}
+
+struct MergeSorted<'a, T, F, U> {
+    heap: BinaryHeap<(U, usize)>,
+    iterators: Vec<slice::Iter<'a, T>>,
+    f: F,
+}
+
+impl<'a, T, F, U> Iterator for MergeSorted<'a, T, F, U>
+where
+    T: Ord + 'a,
+    F: Fn(&'a T) -> U,
+    U: Ord + 'a,
+{
+    type Item = U;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (item, i) = self.heap.pop()?;
+        if let Some(next_item) = self.iterators[i].next() {
+            self.heap.push(((self.f)(next_item), i));
         }
-        if let Some(item) = iterators[i].next() {
-            heap.push((f(item), i));
+        Some(item)
+    }
+}
+
+/// Merges consecutive levels quoting the same price into a single level, keeping the amount
+/// contributed by each exchange. `levels` must already be sorted.
+fn consolidate_levels<'a>(
+    levels: impl Iterator<Item = &'a Level>,
+    max_levels: usize,
+) -> Vec<PriceLevel> {
+    let mut result: Vec<PriceLevel> = Vec::with_capacity(max_levels);
+    for level in levels {
+        match result.last_mut() {
+            Some(last) if last.price == level.price => {
+                last.add_amount(&level.exchange_name, level.amount)
+            }
+            _ => {
+                if result.len() == max_levels {
+                    break;
+                }
+                let mut consolidated = PriceLevel::new(level.price);
+                consolidated.add_amount(&level.exchange_name, level.amount);
+                result.push(consolidated);
+            }
         }
     }
     result
 }
 
-impl From<&Level> for orderbook::Level {
-    fn from(value: &Level) -> Self {
+impl Level {
+    pub fn to_proto(&self, scale: &ScaleConfig) -> orderbook::Level {
+        orderbook::Level {
+            price: to_f64(self.price),
+            amount: to_f64(self.amount),
+            exchange: self.exchange_name.clone(),
+            source: self.source.into(),
+            price_decimal: format_scaled(self.price, scale.price),
+            amount_decimal: format_scaled(self.amount, scale.quantity),
+        }
+    }
+}
+
+/// Total amount at a price with the part contributed by each exchange, published as an
+/// `orderbook::ConsolidatedLevel`.
+#[derive(Debug, Default)]
+pub(crate) struct PriceLevel {
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub exchanges: Vec<(String, Decimal)>,
+}
+
+impl PriceLevel {
+    pub(crate) fn new(price: Decimal) -> Self {
         Self {
-            price: value.price.into(),
-            amount: value.amount.into(),
-            exchange: value.exchange_name.clone(),
+            price,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn add_amount(&mut self, exchange: &str, amount: Decimal) {
+        self.amount += amount;
+        match self.exchanges.iter_mut().find(|(e, _)| e == exchange) {
+            Some((_, exchange_amount)) => *exchange_amount += amount,
+            None => self.exchanges.push((exchange.to_owned(), amount)),
+        }
+    }
+
+    pub(crate) fn to_proto(&self, scale: &ScaleConfig) -> orderbook::ConsolidatedLevel {
+        orderbook::ConsolidatedLevel {
+            price: to_f64(self.price),
+            amount: to_f64(self.amount),
+            exchanges: self
+                .exchanges
+                .iter()
+                .map(|(exchange, amount)| orderbook::ExchangeAmount {
+                    exchange: exchange.clone(),
+                    amount: to_f64(*amount),
+                    amount_decimal: format_scaled(*amount, scale.quantity),
+                })
+                .collect(),
+            price_decimal: format_scaled(self.price, scale.price),
+            amount_decimal: format_scaled(self.amount, scale.quantity),
+            partial: false,
         }
     }
 }
@@ -136,6 +462,7 @@ mod tests {
                 price: i.price,
                 amount: i.amount,
                 exchange_name: i.exchange_name.clone(),
+                source: i.source,
             })
             .collect();
         assert_eq!(
@@ -147,8 +474,21 @@ mod tests {
     fn level(price: f64, exchange_name: &str) -> Level {
         Level {
             price: price.try_into().unwrap(),
-            amount: NotNan::try_from(0.0).unwrap(),
+            amount: Decimal::ZERO,
             exchange_name: exchange_name.into(),
+            source: LevelSource::Direct,
+        }
+    }
+
+    fn proto_level(price: f64, exchange_name: &str) -> orderbook::Level {
+        level(price, exchange_name).to_proto(&ScaleConfig::default())
+    }
+
+    fn config(max_levels: usize, consolidate: bool) -> AggregatorConfig {
+        AggregatorConfig {
+            max_levels,
+            consolidate,
+            ..Default::default()
         }
     }
 
@@ -162,16 +502,19 @@ mod tests {
         let ask_c = vec![level(13.5, "c"), level(18.8, "c"), level(80.9, "c")];
         let exc_a = ExchangeOrders {
             exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
             asks: ask_a,
             bids: bid_a,
         };
         let exc_b = ExchangeOrders {
             exchange_name: "b".to_owned(),
+            symbol: "ab".to_owned(),
             asks: ask_b,
             bids: bid_b,
         };
         let exc_c = ExchangeOrders {
             exchange_name: "c".to_owned(),
+            symbol: "ab".to_owned(),
             asks: ask_c,
             bids: bid_c,
         };
@@ -179,64 +522,183 @@ mod tests {
         exchanges.insert("a".to_owned(), exc_a);
         exchanges.insert("b".to_owned(), exc_b);
         exchanges.insert("c".to_owned(), exc_c);
-        let summary = sort_orders_and_calculate_spread(&exchanges, 5);
+        let summary = sort_orders_and_calculate_spread(exchanges.values(), &config(5, false));
         let expected = Summary {
             spread: 1.5,
+            spread_decimal: "1.50000000".to_owned(),
+            price_scale: 8,
+            quantity_scale: 8,
             bids: vec![
-                orderbook::Level {
-                    exchange: "b".to_owned(),
-                    price: 12.0,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "c".to_owned(),
-                    price: 11.0,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "a".to_owned(),
-                    price: 10.0,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "c".to_owned(),
-                    price: 9.3,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "b".to_owned(),
-                    price: 9.2,
-                    amount: 0.0,
-                },
+                proto_level(12.0, "b"),
+                proto_level(11.0, "c"),
+                proto_level(10.0, "a"),
+                proto_level(9.3, "c"),
+                proto_level(9.2, "b"),
             ],
             asks: vec![
-                orderbook::Level {
-                    exchange: "c".to_owned(),
-                    price: 13.5,
-                    amount: 0.0,
-                },
-                orderbook::Level {
+                proto_level(13.5, "c"),
+                proto_level(15.0, "a"),
+                proto_level(18.8, "c"),
+                proto_level(20.0, "b"),
+                proto_level(20.9, "a"),
+            ],
+            bids_truncated: true,
+            asks_truncated: true,
+            ..Default::default()
+        };
+        assert_eq!(expected, summary);
+    }
+
+    #[test]
+    fn test_best_bid_offer() {
+        let direct = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(15.0, "a"), level(16.0, "a")],
+            bids: vec![level(10.0, "a"), level(9.0, "a")],
+        };
+        let synthetic = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(14.0, "a")],
+            bids: vec![level(9.5, "a")],
+        };
+        let other = ExchangeOrders {
+            exchange_name: "b".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![],
+            bids: vec![level(11.0, "b")],
+        };
+        let bbo = best_bid_offer([&other, &direct, &synthetic], &ScaleConfig::default());
+        let expected = BestBidOffer {
+            exchanges: vec![
+                ExchangeBestBidOffer {
                     exchange: "a".to_owned(),
-                    price: 15.0,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "c".to_owned(),
-                    price: 18.8,
-                    amount: 0.0,
+                    best_bid: Some(proto_level(10.0, "a")),
+                    best_ask: Some(proto_level(14.0, "a")),
                 },
-                orderbook::Level {
+                ExchangeBestBidOffer {
                     exchange: "b".to_owned(),
-                    price: 20.0,
-                    amount: 0.0,
-                },
-                orderbook::Level {
-                    exchange: "a".to_owned(),
-                    price: 20.9,
-                    amount: 0.0,
+                    best_bid: Some(proto_level(11.0, "b")),
+                    best_ask: None,
                 },
             ],
+            best_bid: Some(proto_level(11.0, "b")),
+            best_ask: Some(proto_level(14.0, "a")),
         };
-        assert_eq!(expected, summary);
+        assert_eq!(expected, bbo);
+    }
+
+    #[test]
+    fn test_exact_spread() {
+        let decimal_level = |price: &str, exchange_name: &str| Level {
+            price: price.parse().unwrap(),
+            ..level(0.0, exchange_name)
+        };
+        let exc_a = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![decimal_level("0.06513", "a")],
+            bids: vec![decimal_level("0.06512", "a")],
+        };
+        let config = AggregatorConfig {
+            scale: ScaleConfig {
+                price: 5,
+                quantity: 4,
+            },
+            ..config(1, false)
+        };
+        let summary = sort_orders_and_calculate_spread([&exc_a], &config);
+        assert_eq!("0.00001", summary.spread_decimal);
+        assert_eq!(0.00001, summary.spread);
+        assert_eq!("0.06512", summary.bids[0].price_decimal);
+        assert_eq!("0.0000", summary.bids[0].amount_decimal);
+    }
+
+    #[test]
+    fn test_fee_adjusted_ordering() {
+        let exc_a = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(100.0, "a")],
+            bids: vec![level(99.0, "a")],
+        };
+        let exc_b = ExchangeOrders {
+            exchange_name: "b".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(100.05, "b")],
+            bids: vec![level(98.95, "b")],
+        };
+        let fees_a = FeeConfig {
+            taker_bps: 10.0,
+            maker_bps: 0.0,
+        };
+        let mut exchanges = HashMap::new();
+        exchanges.insert("a".to_owned(), exc_a.fee_adjusted(&fees_a, 8));
+        exchanges.insert("b".to_owned(), exc_b.fee_adjusted(&FeeConfig::default(), 8));
+        let summary = sort_orders_and_calculate_spread(exchanges.values(), &config(1, false));
+        assert_eq!("b", summary.asks[0].exchange);
+        assert_eq!(100.05, summary.asks[0].price);
+        assert_eq!("b", summary.bids[0].exchange);
+        assert_eq!(98.95, summary.bids[0].price);
+    }
+
+    #[test]
+    fn test_consolidating_levels() {
+        let amount_level = |price: f64, amount: f64, exchange_name: &str| Level {
+            amount: amount.try_into().unwrap(),
+            ..level(price, exchange_name)
+        };
+        let exc_a = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![amount_level(13.0, 1.0, "a"), amount_level(14.0, 1.0, "a")],
+            bids: vec![amount_level(12.0, 1.0, "a"), amount_level(11.0, 2.0, "a")],
+        };
+        let exc_b = ExchangeOrders {
+            exchange_name: "b".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![amount_level(13.0, 3.0, "b"), amount_level(15.0, 1.0, "b")],
+            bids: vec![amount_level(11.5, 1.0, "b"), amount_level(11.0, 0.5, "b")],
+        };
+        let mut exchanges = HashMap::new();
+        exchanges.insert("a".to_owned(), exc_a);
+        exchanges.insert("b".to_owned(), exc_b);
+        let summary = sort_orders_and_calculate_spread(exchanges.values(), &config(2, true));
+        let consolidated = |price: f64, exchanges: &[(&str, f64)]| orderbook::ConsolidatedLevel {
+            price,
+            amount: exchanges.iter().map(|(_, amount)| amount).sum(),
+            exchanges: exchanges
+                .iter()
+                .map(|(exchange, amount)| orderbook::ExchangeAmount {
+                    exchange: exchange.to_string(),
+                    amount: *amount,
+                    amount_decimal: format!("{amount:.8}"),
+                })
+                .collect(),
+            price_decimal: format!("{price:.8}"),
+            amount_decimal: format!(
+                "{:.8}",
+                exchanges.iter().map(|(_, amount)| amount).sum::<f64>()
+            ),
+            partial: false,
+        };
+        assert_eq!(2, summary.asks.len());
+        assert!(summary.asks_truncated);
+        assert!(summary.bids_truncated);
+        assert_eq!(
+            vec![
+                consolidated(13.0, &[("a", 1.0), ("b", 3.0)]),
+                consolidated(14.0, &[("a", 1.0)]),
+            ],
+            summary.consolidated_asks
+        );
+        assert_eq!(
+            vec![
+                consolidated(12.0, &[("a", 1.0)]),
+                consolidated(11.5, &[("b", 1.0)]),
+            ],
+            summary.consolidated_bids
+        );
     }
 }
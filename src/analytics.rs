@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+
+use log::info;
+use rust_decimal::Decimal;
+use tokio::sync::watch;
+
+use crate::configuration::AnalyticsConfig;
+use crate::decimal::{parse_or, to_f64};
+use crate::orderbook::{self, Analytics, ExchangeDepth, ExchangeShare, Imbalance, Summary};
+
+/// Publishes the analytics of every summary.
+pub async fn analytics_publisher(
+    mut summaries: watch::Receiver<Summary>,
+    sender: watch::Sender<Analytics>,
+    config: AnalyticsConfig,
+) -> anyhow::Result<()> {
+    while summaries.changed().await.is_ok() {
+        let analytics = book_analytics(&summaries.borrow_and_update(), &config);
+        if sender.send(analytics).is_err() {
+            info!("analytics channel closed. Exiting");
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct Side {
+    /// (exchange, price, amount) from the best price.
+    levels: Vec<(String, Decimal, Decimal)>,
+}
+
+impl Side {
+    fn new(levels: &[orderbook::Level]) -> Self {
+        Self {
+            levels: levels
+                .iter()
+                .map(|level| {
+                    (
+                        level.exchange.clone(),
+                        parse_or(&level.price_decimal, level.price),
+                        parse_or(&level.amount_decimal, level.amount),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn best_price(&self) -> Option<Decimal> {
+        self.levels.first().map(|(_, price, _)| *price)
+    }
+
+    /// Amount quoted at the best price by every exchange.
+    fn best_amount(&self) -> Decimal {
+        let best_price = self.best_price();
+        self.levels
+            .iter()
+            .take_while(|(_, price, _)| Some(*price) == best_price)
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+
+    fn amount(&self, levels: usize) -> Decimal {
+        self.levels
+            .iter()
+            .take(levels)
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+}
+
+/// Computes the analytics of the aggregated book. Books missing a side only get the exchange
+/// shares.
+pub fn book_analytics(summary: &Summary, config: &AnalyticsConfig) -> Analytics {
+    let bids = Side::new(&summary.bids);
+    let asks = Side::new(&summary.asks);
+    let mut analytics = Analytics {
+        shares: exchange_shares(&bids, &asks),
+        ..Default::default()
+    };
+    let (Some(best_bid), Some(best_ask)) = (bids.best_price(), asks.best_price()) else {
+        return analytics;
+    };
+    let mid = (best_bid + best_ask) / Decimal::TWO;
+    let (bid_amount, ask_amount) = (bids.best_amount(), asks.best_amount());
+    let microprice = if (bid_amount + ask_amount).is_zero() {
+        mid
+    } else {
+        (best_bid * ask_amount + best_ask * bid_amount) / (bid_amount + ask_amount)
+    };
+    analytics.mid_price = to_f64(mid);
+    analytics.microprice = to_f64(microprice);
+    analytics.imbalances = config
+        .imbalance_levels
+        .iter()
+        .map(|&levels| Imbalance {
+            levels,
+            imbalance: imbalance(bids.amount(levels as usize), asks.amount(levels as usize)),
+        })
+        .collect();
+    analytics.depths = config
+        .depth_bps
+        .iter()
+        .flat_map(|&bps| depth_within(&bids, &asks, mid, bps))
+        .collect();
+    analytics
+}
+
+/// From -1 when there are only asks to 1 when there are only bids.
+fn imbalance(bid_amount: Decimal, ask_amount: Decimal) -> f64 {
+    let total = bid_amount + ask_amount;
+    if total.is_zero() {
+        0.0
+    } else {
+        to_f64((bid_amount - ask_amount) / total)
+    }
+}
+
+/// Amount quoted by each exchange within `bps` basis points of `mid`.
+fn depth_within(bids: &Side, asks: &Side, mid: Decimal, bps: u32) -> Vec<ExchangeDepth> {
+    let distance = mid * Decimal::from(bps) / Decimal::from(10_000);
+    let mut depths: BTreeMap<&str, (Decimal, Decimal)> = BTreeMap::new();
+    for (exchange, price, amount) in &bids.levels {
+        if *price >= mid - distance {
+            depths.entry(exchange).or_default().0 += amount;
+        }
+    }
+    for (exchange, price, amount) in &asks.levels {
+        if *price <= mid + distance {
+            depths.entry(exchange).or_default().1 += amount;
+        }
+    }
+    depths
+        .into_iter()
+        .map(|(exchange, (bid_amount, ask_amount))| ExchangeDepth {
+            exchange: exchange.to_owned(),
+            bps,
+            bid_amount: to_f64(bid_amount),
+            ask_amount: to_f64(ask_amount),
+        })
+        .collect()
+}
+
+/// Part of the amount of the aggregated levels quoted by each exchange.
+fn exchange_shares(bids: &Side, asks: &Side) -> Vec<ExchangeShare> {
+    let mut amounts: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for (exchange, _, amount) in bids.levels.iter().chain(&asks.levels) {
+        *amounts.entry(exchange).or_default() += amount;
+    }
+    let total: Decimal = amounts.values().sum();
+    if total.is_zero() {
+        return vec![];
+    }
+    amounts
+        .into_iter()
+        .map(|(exchange, amount)| ExchangeShare {
+            exchange: exchange.to_owned(),
+            share: to_f64(amount / total),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn level(exchange: &str, price: &str, amount: &str) -> orderbook::Level {
+        orderbook::Level {
+            exchange: exchange.to_owned(),
+            price_decimal: price.to_owned(),
+            amount_decimal: amount.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_book_analytics() {
+        let summary = Summary {
+            bids: vec![
+                level("a", "99", "1"),
+                level("b", "99", "2"),
+                level("a", "98", "3"),
+            ],
+            asks: vec![level("b", "101", "1"), level("b", "103", "3")],
+            ..Default::default()
+        };
+        let config = AnalyticsConfig {
+            imbalance_levels: vec![1, 3],
+            depth_bps: vec![150],
+        };
+        let analytics = book_analytics(&summary, &config);
+        let expected = Analytics {
+            mid_price: 100.0,
+            // (99 * 1 + 101 * 3) / 4
+            microprice: 100.5,
+            imbalances: vec![
+                Imbalance {
+                    levels: 1,
+                    imbalance: 0.0,
+                },
+                Imbalance {
+                    levels: 3,
+                    imbalance: 0.2,
+                },
+            ],
+            depths: vec![
+                ExchangeDepth {
+                    exchange: "a".to_owned(),
+                    bps: 150,
+                    bid_amount: 1.0,
+                    ask_amount: 0.0,
+                },
+                ExchangeDepth {
+                    exchange: "b".to_owned(),
+                    bps: 150,
+                    bid_amount: 2.0,
+                    ask_amount: 1.0,
+                },
+            ],
+            shares: vec![
+                ExchangeShare {
+                    exchange: "a".to_owned(),
+                    share: 0.4,
+                },
+                ExchangeShare {
+                    exchange: "b".to_owned(),
+                    share: 0.6,
+                },
+            ],
+        };
+        assert_eq!(expected, analytics);
+    }
+
+    #[test]
+    fn test_one_sided_book() {
+        let summary = Summary {
+            bids: vec![level("a", "99", "1")],
+            ..Default::default()
+        };
+        let analytics = book_analytics(&summary, &AnalyticsConfig::default());
+        assert_eq!(0.0, analytics.mid_price);
+        assert!(analytics.imbalances.is_empty());
+        assert_eq!(1, analytics.shares.len());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use rust_decimal::Decimal;
+
+use crate::aggregator::{AggregatorConfig, ExchangeOrders};
+use crate::common::unix_millis;
+use crate::configuration::FeeConfig;
+use crate::decimal::{format_scaled, to_f64};
+use crate::orderbook::Opportunity;
+
+/// Tracks crossed books between every pair of exchanges across updates, so opportunities can be
+/// reported with the time they opened and how long they lasted.
+#[derive(Debug, Default)]
+pub struct ArbitrageDetector {
+    open: HashMap<(String, String), (Crossing, Opportunity)>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Crossing {
+    buy_price: Decimal,
+    sell_price: Decimal,
+    size: Decimal,
+    profit: Decimal,
+}
+
+impl ArbitrageDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the opportunities that opened, changed or closed with the latest exchange orders.
+    pub fn update(
+        &mut self,
+        exchanges: &HashMap<String, ExchangeOrders>,
+        config: &AggregatorConfig,
+        now: SystemTime,
+    ) -> Vec<Opportunity> {
+        let now_ms = unix_millis(now);
+        let mut events = vec![];
+        let mut open = HashMap::new();
+        for buy in exchanges.values() {
+            for sell in exchanges.values() {
+                if buy.exchange_name == sell.exchange_name {
+                    continue;
+                }
+                let Some(crossing) = find_crossing(
+                    buy,
+                    &config.fees_for(&buy.exchange_name),
+                    sell,
+                    &config.fees_for(&sell.exchange_name),
+                ) else {
+                    continue;
+                };
+                let key = (buy.exchange_name.clone(), sell.exchange_name.clone());
+                let opportunity = match self.open.remove(&key) {
+                    Some((previous_crossing, previous)) if crossing == previous_crossing => {
+                        previous
+                    }
+                    previous => {
+                        let opened_at_ms = previous.map_or(now_ms, |(_, p)| p.opened_at_ms);
+                        let (price_scale, quantity_scale) =
+                            (config.scale.price, config.scale.quantity);
+                        let opportunity = Opportunity {
+                            buy_exchange: key.0.clone(),
+                            sell_exchange: key.1.clone(),
+                            buy_price: to_f64(crossing.buy_price),
+                            sell_price: to_f64(crossing.sell_price),
+                            size: to_f64(crossing.size),
+                            profit: to_f64(crossing.profit),
+                            opened_at_ms,
+                            updated_at_ms: now_ms,
+                            duration_ms: now_ms.saturating_sub(opened_at_ms),
+                            closed: false,
+                            buy_price_decimal: format_scaled(crossing.buy_price, price_scale),
+                            sell_price_decimal: format_scaled(crossing.sell_price, price_scale),
+                            size_decimal: format_scaled(crossing.size, quantity_scale),
+                            profit_decimal: format_scaled(crossing.profit, price_scale),
+                        };
+                        events.push(opportunity.clone());
+                        opportunity
+                    }
+                };
+                open.insert(key, (crossing, opportunity));
+            }
+        }
+        for (_, (_, mut closed)) in self.open.drain() {
+            closed.closed = true;
+            closed.updated_at_ms = now_ms;
+            closed.duration_ms = now_ms.saturating_sub(closed.opened_at_ms);
+            events.push(closed);
+        }
+        self.open = open;
+        events
+    }
+}
+
+/// Walks the asks of `buy` and the bids of `sell` while selling still pays more than buying after
+/// taker fees on both sides.
+fn find_crossing(
+    buy: &ExchangeOrders,
+    buy_fees: &FeeConfig,
+    sell: &ExchangeOrders,
+    sell_fees: &FeeConfig,
+) -> Option<Crossing> {
+    let buy_fee = buy_fees.taker_fee_decimal();
+    let sell_fee = sell_fees.taker_fee_decimal();
+    let mut asks = buy.asks.iter().map(|l| (l.price, l.amount));
+    let mut bids = sell.bids.iter().map(|l| (l.price, l.amount));
+    let (buy_price, mut ask_left) = asks.next()?;
+    let (sell_price, mut bid_left) = bids.next()?;
+    let (mut ask_price, mut bid_price) = (buy_price, sell_price);
+    let mut size = Decimal::ZERO;
+    let mut profit = Decimal::ZERO;
+    loop {
+        let margin = bid_price * (Decimal::ONE - sell_fee) - ask_price * (Decimal::ONE + buy_fee);
+        if margin <= Decimal::ZERO {
+            break;
+        }
+        let quantity = ask_left.min(bid_left);
+        size += quantity;
+        profit += margin * quantity;
+        ask_left -= quantity;
+        bid_left -= quantity;
+        if ask_left <= Decimal::ZERO {
+            match asks.next() {
+                Some((price, amount)) => (ask_price, ask_left) = (price, amount),
+                None => break,
+            }
+        }
+        if bid_left <= Decimal::ZERO {
+            match bids.next() {
+                Some((price, amount)) => (bid_price, bid_left) = (price, amount),
+                None => break,
+            }
+        }
+    }
+    (size > Decimal::ZERO).then_some(Crossing {
+        buy_price,
+        sell_price,
+        size,
+        profit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::aggregator::Level;
+    use crate::orderbook::LevelSource;
+
+    use super::*;
+
+    fn level(price: f64, amount: f64, exchange_name: &str) -> Level {
+        Level {
+            price: price.try_into().unwrap(),
+            amount: amount.try_into().unwrap(),
+            exchange_name: exchange_name.into(),
+            source: LevelSource::Direct,
+        }
+    }
+
+    fn exchanges(b_bids: Vec<Level>) -> HashMap<String, ExchangeOrders> {
+        let a = ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(100.0, 1.0, "a"), level(101.0, 2.0, "a")],
+            bids: vec![level(99.0, 1.0, "a")],
+        };
+        let b = ExchangeOrders {
+            exchange_name: "b".to_owned(),
+            symbol: "ab".to_owned(),
+            asks: vec![level(103.0, 1.0, "b")],
+            bids: b_bids,
+        };
+        HashMap::from([("a".to_owned(), a), ("b".to_owned(), b)])
+    }
+
+    #[test]
+    fn test_crossed_books() {
+        let mut detector = ArbitrageDetector::new();
+        let config = AggregatorConfig::default();
+        let crossed = exchanges(vec![level(102.0, 1.5, "b"), level(100.5, 5.0, "b")]);
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let events = detector.update(&crossed, &config, start);
+        assert_eq!(1, events.len());
+        let opportunity = &events[0];
+        assert_eq!("a", opportunity.buy_exchange);
+        assert_eq!("b", opportunity.sell_exchange);
+        assert_eq!(100.0, opportunity.buy_price);
+        assert_eq!(102.0, opportunity.sell_price);
+        // 1.0 at 100 -> 102, then 0.5 at 101 -> 102
+        assert_eq!(1.5, opportunity.size);
+        assert_eq!(2.5, opportunity.profit);
+        assert_eq!("1.50000000", opportunity.size_decimal);
+        assert_eq!("2.50000000", opportunity.profit_decimal);
+        assert_eq!(1000, opportunity.opened_at_ms);
+
+        assert!(detector
+            .update(&crossed, &config, start + Duration::from_millis(10))
+            .is_empty());
+
+        let events = detector.update(
+            &exchanges(vec![level(99.5, 1.0, "b")]),
+            &config,
+            start + Duration::from_millis(250),
+        );
+        assert_eq!(1, events.len());
+        assert!(events[0].closed);
+        assert_eq!(250, events[0].duration_ms);
+    }
+
+    #[test]
+    fn test_fees_close_the_opportunity() {
+        let mut detector = ArbitrageDetector::new();
+        let fees = FeeConfig {
+            taker_bps: 50.0,
+            maker_bps: 0.0,
+        };
+        let config = AggregatorConfig {
+            fees: HashMap::from([("a".to_owned(), fees), ("b".to_owned(), fees)]),
+            ..Default::default()
+        };
+        let events = detector.update(
+            &exchanges(vec![level(100.5, 1.0, "b")]),
+            &config,
+            SystemTime::now(),
+        );
+        assert!(events.is_empty());
+    }
+}
@@ -1,29 +1,68 @@
-use std::env;
+use std::{env, fs};
 
 use futures::StreamExt;
-use orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use order_aggregator::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use order_aggregator::orderbook::BookSummaryRequest;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
 
-use crate::orderbook::Empty;
-
-pub mod orderbook {
-    tonic::include_proto!("orderbook");
-}
+const USAGE: &str = "Usage: aggregator-client <host> <port> [--ca <file>] [--cert <file> --key <file>] [--domain <name>]";
 
 #[tokio::main]
 async fn main() {
-    let mut args = env::args();
-    if args.len() != 3 {
-        eprintln!("Usage: aggregator-client <host> <port>");
+    let mut args = env::args().skip(1);
+    let (Some(host), Some(port)) = (args.next(), args.next()) else {
+        eprintln!("{USAGE}");
         return;
+    };
+    let port: u16 = port.parse().expect("parsing port");
+    let (mut ca, mut cert, mut key, mut domain) = (None, None, None, None);
+    while let Some(option) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => {
+                eprintln!("{USAGE}");
+                return;
+            }
+        };
+        match option.as_str() {
+            "--ca" => ca = Some(value),
+            "--cert" => cert = Some(value),
+            "--key" => key = Some(value),
+            "--domain" => domain = Some(value),
+            _ => {
+                eprintln!("{USAGE}");
+                return;
+            }
+        }
     }
-    args.next().unwrap();
-    let host = args.next().unwrap();
-    let port: u16 = args.next().unwrap().parse().expect("parsing port");
-    let mut client = OrderbookAggregatorClient::connect(format!("http://{host}:{port}"))
-        .await
-        .expect("connecting");
+    let tls = ca.is_some() || cert.is_some() || key.is_some() || domain.is_some();
+    let scheme = if tls { "https" } else { "http" };
+    let mut endpoint =
+        Channel::from_shared(format!("{scheme}://{host}:{port}")).expect("parsing address");
+    if tls {
+        let read = |path: &str| fs::read(path).expect("reading TLS file");
+        let mut tls_config = ClientTlsConfig::new();
+        if let Some(ca) = ca {
+            tls_config = tls_config.ca_certificate(Certificate::from_pem(read(&ca)));
+        }
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                tls_config = tls_config.identity(Identity::from_pem(read(&cert), read(&key)))
+            }
+            (None, None) => {}
+            _ => {
+                eprintln!("--cert and --key must be set together");
+                return;
+            }
+        }
+        if let Some(domain) = domain {
+            tls_config = tls_config.domain_name(domain);
+        }
+        endpoint = endpoint.tls_config(tls_config).expect("configuring TLS");
+    }
+    let mut client = OrderbookAggregatorClient::new(endpoint.connect().await.expect("connecting"));
     let response = client
-        .book_summary(Empty::default())
+        .book_summary(BookSummaryRequest::default())
         .await
         .expect("calling grpc endpoint");
     let mut response_stream = response.into_inner();
@@ -1,23 +1,37 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::{bail, Context};
 use futures::{SinkExt, StreamExt};
 use log::{info, warn};
+use rust_decimal::Decimal;
+use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{aggregator::ExchangeOrders, common::OrderBookData, configuration::BinanceConfig};
+use crate::{
+    aggregator::ExchangeOrders,
+    common::OrderBookData,
+    configuration::{BinanceConfig, BinanceTradeStream, InstrumentConfig},
+    orderbook::Side,
+    trades::Trade,
+};
+
+pub const EXCHANGE_NAME: &str = "binance";
 
-pub(crate) async fn binance_stream(
+pub async fn binance_stream(
     config: BinanceConfig,
     symbol: String,
+    instrument: Option<InstrumentConfig>,
     orders_sender: Sender<ExchangeOrders>,
 ) -> anyhow::Result<()> {
     let max_levels = config.depth as u8;
     let interval = config.interval as u16;
+    let exchange_symbol = config.symbols.exchange_symbol(&symbol);
     let url = config
         .url
         .join("ws/")
         .context("joining url: ws")?
-        .join(&format!("{symbol}@depth{max_levels}@{interval}ms"))
+        .join(&format!("{exchange_symbol}@depth{max_levels}@{interval}ms"))
         .context("joining url: params")?;
     info!("Connecting to {}", url);
     let (binance_ws, _) = tokio_tungstenite::connect_async(url)
@@ -29,11 +43,21 @@ pub(crate) async fn binance_stream(
             Message::Text(message_text) => {
                 let mut order_book: OrderBookData = serde_json::from_str(&message_text)
                     .with_context(|| format!("parsing message: {message_text}"))?;
+                if let Some(instrument) = &instrument {
+                    let dropped = order_book.retain_valid(instrument);
+                    if dropped > 0 {
+                        warn!("dropped {} levels breaking tick or lot size", dropped);
+                    }
+                }
                 if config.sort {
                     order_book.sort();
                 }
                 let send_result = orders_sender
-                    .send(order_book.into_exchange_orders("binance".to_owned(), max_levels.into()))
+                    .send(order_book.into_exchange_orders(
+                        EXCHANGE_NAME.to_owned(),
+                        symbol.clone(),
+                        max_levels.into(),
+                    ))
                     .await;
                 if send_result.is_err() {
                     info!("channel closed. Exiting.");
@@ -59,3 +83,182 @@ pub(crate) async fn binance_stream(
     }
     Ok(())
 }
+
+pub async fn binance_trades_stream(
+    config: BinanceConfig,
+    symbol: String,
+    trades_sender: Sender<Trade>,
+) -> anyhow::Result<()> {
+    let stream_name = match config.trade_stream {
+        BinanceTradeStream::Trade => "trade",
+        BinanceTradeStream::AggTrade => "aggTrade",
+    };
+    let url = config
+        .url
+        .join("ws/")
+        .context("joining url: ws")?
+        .join(&format!(
+            "{}@{stream_name}",
+            config.symbols.exchange_symbol(&symbol)
+        ))
+        .context("joining url: params")?;
+    info!("Connecting to {}", url);
+    let (binance_ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .context("establishing connection")?;
+    let (mut binance_writer, mut binance_reader) = binance_ws.split();
+    while let Some(read_result) = binance_reader.next().await {
+        match read_result.context("reading packet")? {
+            Message::Text(message_text) => {
+                let trade: TradeMessage = serde_json::from_str(&message_text)
+                    .with_context(|| format!("parsing message: {message_text}"))?;
+                if trades_sender
+                    .send(trade.into_trade(symbol.clone()))
+                    .await
+                    .is_err()
+                {
+                    info!("channel closed. Exiting.");
+                    break;
+                }
+            }
+            Message::Binary(_) => {
+                bail!(format!("unsupported binary message"))
+            }
+            Message::Ping(payload) => {
+                binance_writer
+                    .send(Message::Pong(payload))
+                    .await
+                    .context("sending pong message")?;
+            }
+            Message::Pong(_) => warn!("got PONG. Ignoring."),
+            Message::Close(_) => {
+                info!("got CLOSE. Closing and restarting.");
+                bail!("Closed, restarting");
+            }
+            Message::Frame(_) => warn!("got FRAME. Ignoring."),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "e")]
+enum TradeMessage {
+    #[serde(rename = "trade")]
+    Trade {
+        #[serde(rename = "t")]
+        id: u64,
+        #[serde(flatten)]
+        details: TradeDetails,
+    },
+    #[serde(rename = "aggTrade")]
+    AggTrade {
+        #[serde(rename = "a")]
+        id: u64,
+        #[serde(flatten)]
+        details: TradeDetails,
+    },
+}
+
+#[derive(Deserialize)]
+struct TradeDetails {
+    #[serde(rename = "p")]
+    price: Decimal,
+    #[serde(rename = "q")]
+    quantity: Decimal,
+    #[serde(rename = "T")]
+    trade_time: u64,
+    #[serde(rename = "m")]
+    buyer_is_maker: bool,
+}
+
+impl TradeMessage {
+    fn into_trade(self, symbol: String) -> Trade {
+        let (TradeMessage::Trade { id, details } | TradeMessage::AggTrade { id, details }) = self;
+        Trade {
+            exchange_name: EXCHANGE_NAME.to_owned(),
+            symbol,
+            price: details.price,
+            amount: details.quantity,
+            side: if details.buyer_is_maker {
+                Side::Sell
+            } else {
+                Side::Buy
+            },
+            trade_id: id.to_string(),
+            traded_at: UNIX_EPOCH + Duration::from_millis(details.trade_time),
+            received_at: SystemTime::now(),
+        }
+    }
+}
+
+/// Fetches the trading rules of `symbol` from the exchangeInfo REST endpoint.
+pub async fn fetch_instrument(
+    config: &BinanceConfig,
+    symbol: &str,
+) -> anyhow::Result<InstrumentConfig> {
+    let mut url = config
+        .rest_url
+        .join("api/v3/exchangeInfo")
+        .context("joining url: exchangeInfo")?;
+    url.query_pairs_mut().append_pair(
+        "symbol",
+        &config.symbols.exchange_symbol(symbol).to_uppercase(),
+    );
+    let exchange_info: ExchangeInfo = reqwest::get(url)
+        .await
+        .context("requesting exchange info")?
+        .error_for_status()
+        .context("requesting exchange info")?
+        .json()
+        .await
+        .context("parsing exchange info")?;
+    let Some(symbol_info) = exchange_info.symbols.into_iter().next() else {
+        bail!("symbol {symbol} not found");
+    };
+    let mut instrument = InstrumentConfig {
+        base: symbol_info.base_asset,
+        quote: symbol_info.quote_asset,
+        tick_size: Decimal::ZERO,
+        lot_size: Decimal::ZERO,
+        min_notional: Decimal::ZERO,
+    };
+    for filter in symbol_info.filters {
+        match filter {
+            SymbolFilter::PriceFilter { tick_size } => instrument.tick_size = tick_size,
+            SymbolFilter::LotSize { step_size } => instrument.lot_size = step_size,
+            SymbolFilter::MinNotional { min_notional }
+            | SymbolFilter::Notional { min_notional } => instrument.min_notional = min_notional,
+            SymbolFilter::Other => {}
+        }
+    }
+    Ok(instrument)
+}
+
+#[derive(Deserialize)]
+struct ExchangeInfo {
+    symbols: Vec<SymbolInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SymbolInfo {
+    base_asset: String,
+    quote_asset: String,
+    filters: Vec<SymbolFilter>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "filterType", rename_all = "SCREAMING_SNAKE_CASE")]
+enum SymbolFilter {
+    #[serde(rename_all = "camelCase")]
+    PriceFilter { tick_size: Decimal },
+    #[serde(rename_all = "camelCase")]
+    LotSize { step_size: Decimal },
+    #[serde(rename_all = "camelCase")]
+    MinNotional { min_notional: Decimal },
+    #[serde(rename_all = "camelCase")]
+    Notional { min_notional: Decimal },
+    #[serde(other)]
+    Other,
+}
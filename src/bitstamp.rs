@@ -1,45 +1,58 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::{bail, Context};
 use futures::{SinkExt, StreamExt};
 use log::{info, warn};
+use rust_decimal::Decimal;
 use serde::Deserialize;
 use serde_json::json;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use crate::{
+    aggregator::ExchangeOrders,
+    common::OrderBookData,
+    configuration::{BitstampConfig, InstrumentConfig},
+    instruments::step_from_decimals,
+    orderbook::Side,
+    trades::Trade,
+};
 
-use crate::{aggregator::ExchangeOrders, common::OrderBookData, configuration::BitstampConfig};
+pub const EXCHANGE_NAME: &str = "bitstamp";
 
-pub(crate) async fn bitstamp_stream(
+pub async fn bitstamp_stream(
     config: BitstampConfig,
     symbol: String,
+    instrument: Option<InstrumentConfig>,
     orders_sender: Sender<ExchangeOrders>,
 ) -> anyhow::Result<()> {
-    let (bitstamp_ws, _) = tokio_tungstenite::connect_async(&config.url)
-        .await
-        .context("connecting to ws")?;
-    let (mut bitstamp_writer, mut bitstamp_reader) = bitstamp_ws.split();
-    let channel_name = format!("order_book_{symbol}");
-    let subscribe_message = json!({
-        "event": "bts:subscribe",
-        "data": {
-            "channel": channel_name,
-        }
-    });
-    bitstamp_writer
-        .send(
-            serde_json::to_string(&subscribe_message)
-                .expect("can't fail serialize")
-                .into(),
-        )
-        .await
-        .context("subscribing to bitstamp stream")?;
+    let channel_name = format!("order_book_{}", config.symbols.exchange_symbol(&symbol));
+    let (mut bitstamp_writer, mut bitstamp_reader) =
+        subscribe(&config.url, channel_name).await?.split();
     while let Some(read_result) = bitstamp_reader.next().await {
         match read_result.context("reading packet")? {
             Message::Text(message_text) => {
                 let bitstamp_message: BitstampMessage = serde_json::from_str(&message_text)
                     .with_context(|| format!("parsing message: {message_text}"))?;
-                if let BitstampMessage::Data { data: order_book } = bitstamp_message {
+                if let BitstampMessage::Data {
+                    data: mut order_book,
+                } = bitstamp_message
+                {
+                    if let Some(instrument) = &instrument {
+                        let dropped = order_book.retain_valid(instrument);
+                        if dropped > 0 {
+                            warn!("dropped {} levels breaking tick or lot size", dropped);
+                        }
+                    }
                     let send_result = orders_sender
-                        .send(order_book.into_exchange_orders("bitstamp".to_owned(), config.depth))
+                        .send(order_book.into_exchange_orders(
+                            EXCHANGE_NAME.to_owned(),
+                            symbol.clone(),
+                            config.depth,
+                        ))
                         .await;
                     if send_result.is_err() {
                         info!("binance stream: channel closed. Exiting.");
@@ -69,12 +82,168 @@ pub(crate) async fn bitstamp_stream(
     Ok(())
 }
 
+pub async fn bitstamp_trades_stream(
+    config: BitstampConfig,
+    symbol: String,
+    trades_sender: Sender<Trade>,
+) -> anyhow::Result<()> {
+    let channel_name = format!("live_trades_{}", config.symbols.exchange_symbol(&symbol));
+    let (mut bitstamp_writer, mut bitstamp_reader) =
+        subscribe(&config.url, channel_name).await?.split();
+    while let Some(read_result) = bitstamp_reader.next().await {
+        match read_result.context("reading packet")? {
+            Message::Text(message_text) => {
+                let bitstamp_message: BitstampMessage = serde_json::from_str(&message_text)
+                    .with_context(|| format!("parsing message: {message_text}"))?;
+                if let BitstampMessage::Trade { data } = bitstamp_message {
+                    let trade = data.into_trade(symbol.clone())?;
+                    if trades_sender.send(trade).await.is_err() {
+                        info!("bitstamp trades stream: channel closed. Exiting.");
+                        break;
+                    }
+                } else {
+                    info!("ignoring non-trade message")
+                }
+            }
+            Message::Binary(_) => {
+                bail!(format!(
+                    "bitstamp trades stream: unsupported binary message"
+                ))
+            }
+            Message::Ping(payload) => {
+                bitstamp_writer
+                    .send(Message::Pong(payload))
+                    .await
+                    .context("bitstamp trades stream: sending pong message")?;
+            }
+            Message::Pong(_) => warn!("bitstamp trades stream: got PONG. Ignoring."),
+            Message::Close(_) => {
+                info!("bitstamp trades stream: got CLOSE. Closing and restarting.");
+                bail!("bitstamp trades stream: Closed, restarting");
+            }
+            Message::Frame(_) => warn!("bitstamp trades stream: got FRAME. Ignoring."),
+        }
+    }
+    Ok(())
+}
+
+async fn subscribe(
+    url: &Url,
+    channel_name: String,
+) -> anyhow::Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let (mut bitstamp_ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .context("connecting to ws")?;
+    let subscribe_message = json!({
+        "event": "bts:subscribe",
+        "data": {
+            "channel": channel_name,
+        }
+    });
+    bitstamp_ws
+        .send(
+            serde_json::to_string(&subscribe_message)
+                .expect("can't fail serialize")
+                .into(),
+        )
+        .await
+        .context("subscribing to bitstamp stream")?;
+    Ok(bitstamp_ws)
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "event", rename_all = "lowercase")]
 enum BitstampMessage {
     Data {
         data: OrderBookData,
     },
+    Trade {
+        data: TradeData,
+    },
     #[serde(other)]
     Unknown,
 }
+
+#[derive(Deserialize)]
+struct TradeData {
+    id: u64,
+    amount_str: Decimal,
+    price_str: Decimal,
+    /// 0 for buys and 1 for sells.
+    #[serde(rename = "type")]
+    trade_type: u8,
+    microtimestamp: String,
+}
+
+impl TradeData {
+    fn into_trade(self, symbol: String) -> anyhow::Result<Trade> {
+        let micros: u64 = self
+            .microtimestamp
+            .parse()
+            .with_context(|| format!("parsing microtimestamp {}", self.microtimestamp))?;
+        Ok(Trade {
+            exchange_name: EXCHANGE_NAME.to_owned(),
+            symbol,
+            price: self.price_str,
+            amount: self.amount_str,
+            side: if self.trade_type == 0 {
+                Side::Buy
+            } else {
+                Side::Sell
+            },
+            trade_id: self.id.to_string(),
+            traded_at: UNIX_EPOCH + Duration::from_micros(micros),
+            received_at: SystemTime::now(),
+        })
+    }
+}
+
+/// Fetches the trading rules of `symbol` from the trading-pairs-info REST endpoint.
+pub async fn fetch_instrument(
+    config: &BitstampConfig,
+    symbol: &str,
+) -> anyhow::Result<InstrumentConfig> {
+    let url = config
+        .rest_url
+        .join("api/v2/trading-pairs-info/")
+        .context("joining url: trading-pairs-info")?;
+    let pairs: Vec<TradingPairInfo> = reqwest::get(url)
+        .await
+        .context("requesting trading pairs info")?
+        .error_for_status()
+        .context("requesting trading pairs info")?
+        .json()
+        .await
+        .context("parsing trading pairs info")?;
+    let url_symbol = config.symbols.exchange_symbol(symbol);
+    let Some(pair) = pairs.into_iter().find(|pair| pair.url_symbol == url_symbol) else {
+        bail!("symbol {symbol} not found");
+    };
+    let Some((base, quote)) = pair.name.split_once('/') else {
+        bail!("unexpected trading pair name {}", pair.name);
+    };
+    // minimum_order comes with its currency, like "10.00000000 USD"
+    let min_notional = pair
+        .minimum_order
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .with_context(|| format!("parsing minimum order {}", pair.minimum_order))?;
+    Ok(InstrumentConfig {
+        base: base.to_owned(),
+        quote: quote.to_owned(),
+        tick_size: step_from_decimals(pair.counter_decimals),
+        lot_size: step_from_decimals(pair.base_decimals),
+        min_notional,
+    })
+}
+
+#[derive(Deserialize)]
+struct TradingPairInfo {
+    name: String,
+    url_symbol: String,
+    base_decimals: u32,
+    counter_decimals: u32,
+    minimum_order: String,
+}
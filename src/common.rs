@@ -1,9 +1,11 @@
-use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use ordered_float::NotNan;
+use rust_decimal::Decimal;
 use serde::Deserialize;
 
 use crate::aggregator;
+use crate::configuration::InstrumentConfig;
+use crate::orderbook::LevelSource;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -15,12 +17,12 @@ pub(crate) struct OrderBookData {
 #[derive(Deserialize, Ord, PartialOrd, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", try_from = "(String, String)")]
 pub(crate) struct Level {
-    pub price: NotNan<f64>,
-    pub quantity: NotNan<f64>,
+    pub price: Decimal,
+    pub quantity: Decimal,
 }
 
 impl TryFrom<(String, String)> for Level {
-    type Error = <NotNan<f64> as FromStr>::Err;
+    type Error = rust_decimal::Error;
 
     fn try_from(value: (String, String)) -> Result<Self, Self::Error> {
         Ok(Self {
@@ -34,6 +36,7 @@ impl OrderBookData {
     pub fn into_exchange_orders(
         self,
         exchange_name: String,
+        symbol: String,
         max_levels: usize,
     ) -> aggregator::ExchangeOrders {
         aggregator::ExchangeOrders {
@@ -50,9 +53,19 @@ impl OrderBookData {
                 .map(|l| l.into_aggregator_level(exchange_name.clone()))
                 .collect(),
             exchange_name,
+            symbol,
         }
     }
 
+    /// Drops the levels that break the tick or lot size of the instrument, returning how many.
+    pub fn retain_valid(&mut self, instrument: &InstrumentConfig) -> usize {
+        let len = self.asks.len() + self.bids.len();
+        let is_valid = |level: &Level| instrument.is_valid_level(level.price, level.quantity);
+        self.asks.retain(is_valid);
+        self.bids.retain(is_valid);
+        len - self.asks.len() - self.bids.len()
+    }
+
     pub fn sort(&mut self) {
         self.asks.sort_unstable();
         self.bids.sort_unstable_by(|a, b| b.cmp(a));
@@ -65,6 +78,13 @@ impl Level {
             exchange_name,
             price: self.price,
             amount: self.quantity,
+            source: LevelSource::Direct,
         }
     }
 }
+
+/// Milliseconds since the Unix epoch, 0 for earlier times.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
@@ -1,10 +1,27 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use serde::Deserialize;
+use config::Environment;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
 use url::Url;
 
+use crate::instruments::InstrumentRegistry;
+use crate::symbols::{get_ignore_case, SymbolAliases, SymbolRules};
+use crate::{binance, bitstamp};
+
+/// Environment variables overriding the configuration file, like `APP_SERVER_PORT`. Values are
+/// only converted when deserialized, so string settings that look like numbers are kept as is.
+pub fn environment() -> Environment {
+    Environment::with_prefix("APP").separator("_")
+}
+
 #[derive(Debug, Deserialize, Clone)]
-pub(crate) struct AppConfig {
+pub struct AppConfig {
+    /// Aggregated symbol, either canonical `BASE/QUOTE` or as written by every exchange.
     pub symbol: String,
     pub binance: BinanceConfig,
     pub bitstamp: BitstampConfig,
@@ -12,15 +29,103 @@ pub(crate) struct AppConfig {
     pub backoff: BackoffConfig,
     pub max_aggregated_levels: usize,
     pub channel_size: usize,
+    #[serde(default)]
+    pub consolidate_levels: bool,
+    #[serde(default)]
+    pub fee_adjusted: bool,
+    pub synthetic: Option<SyntheticConfig>,
+    /// Scale of the symbols without an entry in `scales` nor instruments to derive it from.
+    #[serde(default)]
+    pub scale: ScaleConfig,
+    /// Scale by symbol, taking precedence over the one derived from the instruments.
+    #[serde(default)]
+    pub scales: HashMap<String, ScaleConfig>,
+    /// Instruments by exchange and symbol. They take precedence over the fetched ones.
+    #[serde(default)]
+    pub instruments: HashMap<String, HashMap<String, InstrumentConfig>>,
+    /// Fetch the instruments missing from `instruments` from the exchanges at startup.
+    #[serde(default)]
+    pub fetch_instruments: bool,
+    /// Subscribes to the trades of `symbol` on every exchange.
+    pub trades: Option<TradesConfig>,
+    #[serde(default)]
+    pub analytics: AnalyticsConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Writes the summaries to daily files.
+    pub file_sink: Option<FileSinkConfig>,
+    /// Consumers of the summaries. See [`AppConfig::sinks`] for the default.
+    pub sinks: Option<Vec<SinkConfig>>,
+}
+
+impl AppConfig {
+    /// Symbols subscribed on every exchange: `symbol` and the synthetic legs, if any.
+    pub fn symbols(&self) -> Vec<String> {
+        let mut symbols = vec![self.symbol.clone()];
+        if let Some(synthetic) = &self.synthetic {
+            symbols.push(synthetic.base_leg.clone());
+            symbols.push(synthetic.quote_leg.clone());
+        }
+        symbols
+    }
+
+    /// Scale of `symbol`: its `scales` entry, else the finest tick and lot sizes of its
+    /// instruments, else the default `scale`.
+    pub fn scale_for(&self, symbol: &str, instruments: &InstrumentRegistry) -> ScaleConfig {
+        get_ignore_case(&self.scales, symbol)
+            .copied()
+            .or_else(|| instruments.scale(symbol))
+            .unwrap_or(self.scale)
+    }
+
+    /// Names of `symbol` accepted from the clients of the sinks.
+    pub fn symbol_aliases(&self) -> SymbolAliases {
+        SymbolAliases::new(
+            self.symbol.clone(),
+            self.exchange_symbol_rules().into_values().collect(),
+        )
+    }
+
+    /// Symbol rules by exchange name.
+    pub fn exchange_symbol_rules(&self) -> HashMap<String, SymbolRules> {
+        HashMap::from([
+            (
+                binance::EXCHANGE_NAME.to_owned(),
+                self.binance.symbols.clone(),
+            ),
+            (
+                bitstamp::EXCHANGE_NAME.to_owned(),
+                self.bitstamp.symbols.clone(),
+            ),
+        ])
+    }
+
+    /// The configured `sinks` or, when not set, the gRPC server and the `[file_sink]` if any.
+    pub fn sinks(&self) -> Vec<SinkConfig> {
+        match &self.sinks {
+            Some(sinks) => sinks.clone(),
+            None => std::iter::once(SinkConfig::Grpc)
+                .chain(self.file_sink.clone().map(SinkConfig::File))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
-pub(crate) struct BinanceConfig {
+pub struct BinanceConfig {
     pub url: Url,
     pub depth: BinanceDepth,
     pub interval: BinanceInterval,
     #[serde(default)]
     pub sort: bool,
+    #[serde(default)]
+    pub fees: FeeConfig,
+    #[serde(default = "default_binance_rest_url")]
+    pub rest_url: Url,
+    #[serde(default)]
+    pub symbols: SymbolRules,
+    #[serde(default)]
+    pub trade_stream: BinanceTradeStream,
 }
 
 #[derive(Debug, Deserialize, Copy, Clone)]
@@ -36,17 +141,330 @@ pub enum BinanceInterval {
     I1000 = 1000,
 }
 
+/// `trade` streams every trade, `aggTrade` the trades of a taker order at the same price as one.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BinanceTradeStream {
+    #[default]
+    Trade,
+    AggTrade,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct BitstampConfig {
     pub url: Url,
     pub depth: usize,
     #[serde(default)]
     pub sort: bool,
+    #[serde(default)]
+    pub fees: FeeConfig,
+    #[serde(default = "default_bitstamp_rest_url")]
+    pub rest_url: Url,
+    #[serde(default)]
+    pub symbols: SymbolRules,
+}
+
+fn default_binance_rest_url() -> Url {
+    Url::parse("https://api.binance.com").expect("valid url")
+}
+
+fn default_bitstamp_rest_url() -> Url {
+    Url::parse("https://www.bitstamp.net").expect("valid url")
+}
+
+/// Exchange fees in basis points of the traded notional. Every computed price assumes crossing
+/// the book, so only the taker fee is applied; the maker fee is kept for library users.
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+pub struct FeeConfig {
+    #[serde(default)]
+    pub taker_bps: f64,
+    #[serde(default)]
+    pub maker_bps: f64,
+}
+
+impl FeeConfig {
+    pub fn taker_fee(&self) -> f64 {
+        self.taker_bps / 10_000.0
+    }
+
+    pub fn maker_fee(&self) -> f64 {
+        self.maker_bps / 10_000.0
+    }
+
+    /// The taker fee as an exact decimal rate.
+    pub fn taker_fee_decimal(&self) -> Decimal {
+        Decimal::try_from(self.taker_bps).unwrap_or_default() / Decimal::from(10_000)
+    }
+}
+
+/// Decimal places of the prices and quantities of a symbol. Exact decimal values are published
+/// with this scale, and derived prices, like fee-adjusted or synthetic ones, are rounded to it.
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+pub struct ScaleConfig {
+    pub price: u32,
+    pub quantity: u32,
+}
+
+impl Default for ScaleConfig {
+    fn default() -> Self {
+        Self {
+            price: 8,
+            quantity: 8,
+        }
+    }
+}
+
+/// Trading rules of a symbol on an exchange.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct InstrumentConfig {
+    pub base: String,
+    pub quote: String,
+    /// Prices must be a multiple of the tick size.
+    pub tick_size: Decimal,
+    /// Amounts must be a multiple of the lot size.
+    pub lot_size: Decimal,
+    /// Smallest order notional, in quote asset.
+    #[serde(default)]
+    pub min_notional: Decimal,
+}
+
+/// Legs of a synthetic book for `symbol` through an intermediate asset. For an ETH/BTC symbol
+/// through USDT, the base leg is ETH/USDT and the quote leg BTC/USDT.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SyntheticConfig {
+    pub base_leg: String,
+    pub quote_leg: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TradesConfig {
+    /// Window of the rolling trade volume.
+    #[serde(with = "humantime_serde", default = "default_volume_window")]
+    pub volume_window: Duration,
+}
+
+fn default_volume_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AnalyticsConfig {
+    /// Numbers of aggregated levels the order book imbalance is computed at.
+    #[serde(default = "default_imbalance_levels")]
+    pub imbalance_levels: Vec<u32>,
+    /// Distances from the mid price, in basis points, the depth of each exchange is computed at.
+    #[serde(default = "default_depth_bps")]
+    pub depth_bps: Vec<u32>,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            imbalance_levels: default_imbalance_levels(),
+            depth_bps: default_depth_bps(),
+        }
+    }
+}
+
+fn default_imbalance_levels() -> Vec<u32> {
+    vec![1, 5, 10]
+}
+
+fn default_depth_bps() -> Vec<u32> {
+    vec![10, 50]
+}
+
+/// Number of bars kept for each resolution. The defaults keep an hour of 1s bars, a day of 1m bars
+/// and a week of 1h bars.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HistoryConfig {
+    #[serde(default = "default_second_bars")]
+    pub second_bars: usize,
+    #[serde(default = "default_minute_bars")]
+    pub minute_bars: usize,
+    #[serde(default = "default_hour_bars")]
+    pub hour_bars: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            second_bars: default_second_bars(),
+            minute_bars: default_minute_bars(),
+            hour_bars: default_hour_bars(),
+        }
+    }
+}
+
+fn default_second_bars() -> usize {
+    3600
+}
+
+fn default_minute_bars() -> usize {
+    1440
+}
+
+fn default_hour_bars() -> usize {
+    168
+}
+
+/// Daily files of the summaries, named `summaries-YYYY-MM-DD` after their UTC date.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct FileSinkConfig {
+    pub directory: PathBuf,
+    #[serde(default = "default_sink_formats")]
+    pub formats: Vec<SinkFormat>,
+    /// Writes the latest summary at this interval instead of every published one.
+    #[serde(with = "humantime_serde", default)]
+    pub interval: Option<Duration>,
+    /// Rows buffered before being written to a Parquet file.
+    #[serde(default = "default_row_group_size")]
+    pub row_group_size: usize,
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkFormat {
+    Csv,
+    Parquet,
+}
+
+fn default_sink_formats() -> Vec<SinkFormat> {
+    vec![SinkFormat::Csv]
+}
+
+fn default_row_group_size() -> usize {
+    1000
+}
+
+/// A consumer of the summaries, selected by its `type`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// The gRPC server, listening as set in `[server]`.
+    Grpc,
+    File(FileSinkConfig),
+    /// JSON lines on the standard output.
+    Stdout,
+    Udp(UdpSinkConfig),
+    WebSocket(WebSocketSinkConfig),
+    Http(HttpSinkConfig),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UdpSinkConfig {
+    /// Destination of the datagrams, usually a multicast group.
+    pub address: SocketAddr,
+    /// Time to live of the IPv4 multicast datagrams.
+    #[serde(default = "default_multicast_ttl")]
+    pub ttl: u32,
+}
+
+/// JSON summaries streamed to the WebSocket clients that subscribe to them.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct WebSocketSinkConfig {
+    pub bind: SocketAddr,
+}
+
+/// The summaries served as JSON and Server-Sent Events over HTTP.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HttpSinkConfig {
+    pub bind: SocketAddr,
+}
+
+fn default_multicast_ttl() -> u32 {
+    1
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Server {
+    /// Port of the default `[::1]` address, used when `bind` is empty.
     pub port: u16,
+    /// Addresses the gRPC server listens on.
+    #[serde(default, deserialize_with = "list_or_comma_separated")]
+    pub bind: Vec<BindAddress>,
+    /// Also accept gRPC-Web requests, over HTTP/1.1 too, from browsers.
+    #[serde(default)]
+    pub grpc_web: bool,
+    /// Origins of the browser pages allowed to call the gRPC-Web service, or `*` for any. Required
+    /// with `grpc_web`.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Serves over TLS when set.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Server {
+    pub fn bind_addresses(&self) -> Vec<BindAddress> {
+        if self.bind.is_empty() {
+            vec![BindAddress::Tcp(format!("[::1]:{}", self.port))]
+        } else {
+            self.bind.clone()
+        }
+    }
+}
+
+/// Lists are comma-separated strings when set from the environment.
+fn list_or_comma_separated<'de, D>(deserializer: D) -> Result<Vec<BindAddress>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Addresses {
+        List(Vec<BindAddress>),
+        CommaSeparated(String),
+    }
+    Ok(match Addresses::deserialize(deserializer)? {
+        Addresses::List(addresses) => addresses,
+        Addresses::CommaSeparated(addresses) => addresses
+            .split(',')
+            .map(|address| BindAddress::from(address.trim().to_owned()))
+            .collect(),
+    })
+}
+
+/// A Unix domain socket path prefixed by `unix:`, or a TCP `host:port` otherwise, like
+/// `0.0.0.0:5000` or `[::]:5000`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(from = "String")]
+pub enum BindAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<String> for BindAddress {
+    fn from(value: String) -> Self {
+        match value.strip_prefix("unix:") {
+            Some(path) => Self::Unix(PathBuf::from(path)),
+            None => Self::Tcp(value),
+        }
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// PEM files of the server certificate chain and key, and of the CA that must have signed the
+/// client certificates for mutual TLS. Changed files are loaded without restarting.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+    /// How often the files are checked for changes.
+    #[serde(with = "humantime_serde", default = "default_reload_interval")]
+    pub reload_interval: Duration,
+}
+
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(60)
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -65,9 +483,7 @@ mod tests {
 
     use super::*;
 
-    #[test]
-    fn test_parse() {
-        let config_string = r#"
+    const CONFIG: &str = r#"
 symbol = "ethbtc"
 max_aggregated_levels = 10
 channel_size = 5
@@ -76,23 +492,208 @@ channel_size = 5
 url = "wss://stream.binance.com:9443"
 depth = "D10"
 interval = "I100"
+trade_stream = "aggTrade"
 
 [bitstamp]
 url = "wss://ws.bitstamp.net"
 depth = 10
 
+[bitstamp.fees]
+taker_bps = 30
+maker_bps = 20
+
+[bitstamp.symbols.overrides]
+"ETH/BTC" = "ethxbt"
+
 [server]
 port = 5000
+bind = ["0.0.0.0:5000", "unix:/run/aggregator.sock"]
+grpc_web = true
+allowed_origins = ["https://dashboard.example.com"]
+
+[server.tls]
+cert = "certs/server.pem"
+key = "certs/server.key"
+client_ca = "certs/ca.pem"
+
+[synthetic]
+base_leg = "ethusdt"
+quote_leg = "btcusdt"
+
+[trades]
+
+[analytics]
+depth_bps = [25]
+
+[file_sink]
+directory = "data"
+formats = ["csv", "parquet"]
+interval = "1s"
+
+[[sinks]]
+type = "grpc"
+
+[[sinks]]
+type = "udp"
+address = "239.1.1.1:5001"
+
+[[sinks]]
+type = "file"
+directory = "data"
+
+[scale]
+price = 2
+quantity = 2
+
+[scales."LTC/BTC"]
+price = 6
+quantity = 3
+
+[instruments.binance.ethbtc]
+base = "ETH"
+quote = "BTC"
+tick_size = 0.00001
+lot_size = "0.0001"
 
 [backoff]
 retries = 5
 min = "100ms"
 max = "10s"
 "#;
+
+    #[test]
+    fn test_parse() {
         let config_reader = Config::builder()
-            .add_source(File::from_str(config_string, FileFormat::Toml))
+            .add_source(File::from_str(CONFIG, FileFormat::Toml))
             .build()
             .expect("building config");
-        let _app_config: AppConfig = config_reader.try_deserialize().expect("deserialize config");
+        let app_config: AppConfig = config_reader.try_deserialize().expect("deserialize config");
+        assert_eq!(FeeConfig::default(), app_config.binance.fees);
+        assert_eq!(
+            vec![
+                BindAddress::Tcp("0.0.0.0:5000".to_owned()),
+                BindAddress::Unix(PathBuf::from("/run/aggregator.sock")),
+            ],
+            app_config.server.bind_addresses()
+        );
+        assert!(app_config.server.grpc_web);
+        assert_eq!(
+            vec!["https://dashboard.example.com".to_owned()],
+            app_config.server.allowed_origins
+        );
+        assert_eq!(
+            Some(TlsConfig {
+                cert: PathBuf::from("certs/server.pem"),
+                key: PathBuf::from("certs/server.key"),
+                client_ca: Some(PathBuf::from("certs/ca.pem")),
+                reload_interval: Duration::from_secs(60),
+            }),
+            app_config.server.tls
+        );
+        assert_eq!(0.003, app_config.bitstamp.fees.taker_fee());
+        assert_eq!(0.002, app_config.bitstamp.fees.maker_fee());
+        assert_eq!(
+            "ethxbt",
+            app_config.bitstamp.symbols.exchange_symbol("ETH/BTC")
+        );
+        assert_eq!(
+            "ethbtc",
+            app_config.binance.symbols.exchange_symbol("ETH/BTC")
+        );
+        assert_eq!(
+            Some(SyntheticConfig {
+                base_leg: "ethusdt".to_owned(),
+                quote_leg: "btcusdt".to_owned(),
+            }),
+            app_config.synthetic
+        );
+        assert_eq!(
+            BinanceTradeStream::AggTrade,
+            app_config.binance.trade_stream
+        );
+        assert_eq!(
+            Some(TradesConfig {
+                volume_window: Duration::from_secs(60)
+            }),
+            app_config.trades
+        );
+        assert_eq!(
+            AnalyticsConfig {
+                imbalance_levels: vec![1, 5, 10],
+                depth_bps: vec![25],
+            },
+            app_config.analytics
+        );
+        assert_eq!(
+            Some(FileSinkConfig {
+                directory: PathBuf::from("data"),
+                formats: vec![SinkFormat::Csv, SinkFormat::Parquet],
+                interval: Some(Duration::from_secs(1)),
+                row_group_size: 1000,
+            }),
+            app_config.file_sink
+        );
+        assert_eq!(
+            vec![
+                SinkConfig::Grpc,
+                SinkConfig::Udp(UdpSinkConfig {
+                    address: "239.1.1.1:5001".parse().unwrap(),
+                    ttl: 1,
+                }),
+                SinkConfig::File(FileSinkConfig {
+                    directory: PathBuf::from("data"),
+                    formats: vec![SinkFormat::Csv],
+                    interval: None,
+                    row_group_size: 1000,
+                }),
+            ],
+            app_config.sinks()
+        );
+        assert_eq!(
+            ScaleConfig {
+                price: 2,
+                quantity: 2
+            },
+            app_config.scale
+        );
+        let instruments = InstrumentRegistry::from_config(&app_config);
+        let scale = |price, quantity| ScaleConfig { price, quantity };
+        assert_eq!(scale(6, 3), app_config.scale_for("LTC/BTC", &instruments));
+        assert_eq!(scale(5, 4), app_config.scale_for("ethbtc", &instruments));
+        assert_eq!(scale(5, 4), app_config.scale_for("ETH/BTC", &instruments));
+        assert_eq!(scale(2, 2), app_config.scale_for("ETH/EUR", &instruments));
+        let instrument = &app_config.instruments["binance"]["ethbtc"];
+        assert_eq!("0.00001".parse::<Decimal>().unwrap(), instrument.tick_size);
+        assert_eq!("0.0001".parse::<Decimal>().unwrap(), instrument.lot_size);
+        assert_eq!(Decimal::ZERO, instrument.min_notional);
+    }
+
+    #[test]
+    fn test_environment() {
+        let variables = HashMap::from([
+            (
+                "APP_SERVER_BIND".to_owned(),
+                "127.0.0.1:5000, unix:/tmp/aggregator.sock".to_owned(),
+            ),
+            // would become 1.1 if values were parsed as numbers
+            ("APP_SYMBOL".to_owned(), "1.10".to_owned()),
+            ("APP_SERVER_PORT".to_owned(), "6000".to_owned()),
+        ]);
+        let app_config: AppConfig = Config::builder()
+            .add_source(File::from_str(CONFIG, FileFormat::Toml))
+            .add_source(environment().source(Some(variables)))
+            .build()
+            .expect("building config")
+            .try_deserialize()
+            .expect("deserialize config");
+        assert_eq!(
+            vec![
+                BindAddress::Tcp("127.0.0.1:5000".to_owned()),
+                BindAddress::Unix(PathBuf::from("/tmp/aggregator.sock")),
+            ],
+            app_config.server.bind
+        );
+        assert_eq!("1.10", app_config.symbol);
+        assert_eq!(6000, app_config.server.port);
     }
 }
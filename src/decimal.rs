@@ -0,0 +1,23 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+/// Formats `value` with exactly `scale` decimal places.
+pub(crate) fn format_scaled(value: Decimal, scale: u32) -> String {
+    let mut scaled = value;
+    scaled.rescale(scale);
+    scaled.to_string()
+}
+
+pub(crate) fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+/// Parses a `*_decimal` field of a published message, falling back to its `double` counterpart
+/// when it is not set.
+pub(crate) fn parse_or(decimal: &str, fallback: f64) -> Decimal {
+    decimal
+        .parse()
+        .ok()
+        .or_else(|| Decimal::try_from(fallback).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::decimal::parse_or;
+use crate::orderbook::{self, level_change::ChangeType, BookDelta, LevelChange, Summary};
+
+/// Levels are identified by their exchange, source and price.
+type LevelKey = (String, i32, String);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Book {
+    bids: BTreeMap<LevelKey, orderbook::Level>,
+    asks: BTreeMap<LevelKey, orderbook::Level>,
+}
+
+/// Turns consecutive summaries into the changes of their levels. The first delta is a snapshot.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    sequence: u64,
+    book: Option<Book>,
+}
+
+impl DeltaEncoder {
+    /// Returns the changes since the previous summary, or `None` when the levels are the same.
+    pub fn encode(&mut self, summary: &Summary) -> Option<BookDelta> {
+        let book = Book::from_summary(summary);
+        let (snapshot, bids, asks) = match &self.book {
+            None => (
+                true,
+                diff(&BTreeMap::new(), &book.bids),
+                diff(&BTreeMap::new(), &book.asks),
+            ),
+            Some(previous) => {
+                let bids = diff(&previous.bids, &book.bids);
+                let asks = diff(&previous.asks, &book.asks);
+                if bids.is_empty() && asks.is_empty() {
+                    return None;
+                }
+                (false, bids, asks)
+            }
+        };
+        let sequence = self.sequence;
+        self.sequence += 1;
+        self.book = Some(book);
+        Some(BookDelta {
+            sequence,
+            snapshot,
+            bids,
+            asks,
+            spread: summary.spread,
+            spread_decimal: summary.spread_decimal.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DeltaError {
+    #[error("no snapshot received yet")]
+    MissingSnapshot,
+    #[error("expected sequence {expected}, received {received}")]
+    Gap { expected: u64, received: u64 },
+}
+
+/// Rebuilds the aggregated book from a `BookDeltas` stream.
+#[derive(Debug, Default)]
+pub struct DeltaBook {
+    sequence: Option<u64>,
+    book: Book,
+}
+
+impl DeltaBook {
+    /// Applies the delta, failing without changes when deltas were missed. Snapshots are always
+    /// applied.
+    pub fn apply(&mut self, delta: &BookDelta) -> Result<(), DeltaError> {
+        if delta.snapshot {
+            self.book = Book::default();
+        } else {
+            let expected = self.sequence.ok_or(DeltaError::MissingSnapshot)? + 1;
+            if delta.sequence != expected {
+                return Err(DeltaError::Gap {
+                    expected,
+                    received: delta.sequence,
+                });
+            }
+        }
+        apply(&mut self.book.bids, &delta.bids);
+        apply(&mut self.book.asks, &delta.asks);
+        self.sequence = Some(delta.sequence);
+        Ok(())
+    }
+
+    /// Bids from the highest price.
+    pub fn bids(&self) -> Vec<orderbook::Level> {
+        let mut bids: Vec<_> = self.book.bids.values().cloned().collect();
+        bids.sort_by_key(|level| std::cmp::Reverse(price(level)));
+        bids
+    }
+
+    /// Asks from the lowest price.
+    pub fn asks(&self) -> Vec<orderbook::Level> {
+        let mut asks: Vec<_> = self.book.asks.values().cloned().collect();
+        asks.sort_by_key(price);
+        asks
+    }
+}
+
+impl Book {
+    fn from_summary(summary: &Summary) -> Self {
+        let by_key = |levels: &[orderbook::Level]| {
+            levels
+                .iter()
+                .map(|level| (key(level), level.clone()))
+                .collect()
+        };
+        Self {
+            bids: by_key(&summary.bids),
+            asks: by_key(&summary.asks),
+        }
+    }
+}
+
+fn key(level: &orderbook::Level) -> LevelKey {
+    (
+        level.exchange.clone(),
+        level.source,
+        price(level).normalize().to_string(),
+    )
+}
+
+fn price(level: &orderbook::Level) -> Decimal {
+    parse_or(&level.price_decimal, level.price)
+}
+
+fn diff(
+    previous: &BTreeMap<LevelKey, orderbook::Level>,
+    current: &BTreeMap<LevelKey, orderbook::Level>,
+) -> Vec<LevelChange> {
+    let change = |change_type: ChangeType, level: &orderbook::Level| LevelChange {
+        change: change_type.into(),
+        level: Some(level.clone()),
+    };
+    let removed = previous
+        .iter()
+        .filter(|(key, _)| !current.contains_key(*key))
+        .map(|(_, level)| change(ChangeType::Removed, level));
+    let inserted_or_updated = current
+        .iter()
+        .filter_map(|(key, level)| match previous.get(key) {
+            None => Some(change(ChangeType::Inserted, level)),
+            Some(previous_level) if previous_level != level => {
+                Some(change(ChangeType::Updated, level))
+            }
+            Some(_) => None,
+        });
+    removed.chain(inserted_or_updated).collect()
+}
+
+fn apply(levels: &mut BTreeMap<LevelKey, orderbook::Level>, changes: &[LevelChange]) {
+    for change in changes {
+        let Some(level) = &change.level else {
+            continue;
+        };
+        if change.change == ChangeType::Removed as i32 {
+            levels.remove(&key(level));
+        } else {
+            levels.insert(key(level), level.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn level(exchange: &str, price: &str, amount: &str) -> orderbook::Level {
+        orderbook::Level {
+            exchange: exchange.to_owned(),
+            price_decimal: price.to_owned(),
+            amount_decimal: amount.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn summary(bids: Vec<orderbook::Level>, asks: Vec<orderbook::Level>) -> Summary {
+        Summary {
+            bids,
+            asks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rebuild_from_deltas() {
+        let summaries = [
+            summary(
+                vec![level("a", "10", "1"), level("b", "9", "2")],
+                vec![level("a", "11", "1")],
+            ),
+            summary(
+                vec![level("b", "9.5", "1"), level("a", "10", "3")],
+                vec![level("a", "11", "1")],
+            ),
+            summary(
+                vec![level("b", "9.5", "1"), level("a", "10", "3")],
+                vec![level("a", "11", "1")],
+            ),
+            summary(
+                vec![level("a", "10", "3")],
+                vec![level("b", "10.5", "1"), level("a", "11", "1")],
+            ),
+        ];
+        let mut encoder = DeltaEncoder::default();
+        let mut book = DeltaBook::default();
+        let mut deltas = vec![];
+        for summary in &summaries {
+            if let Some(delta) = encoder.encode(summary) {
+                book.apply(&delta).unwrap();
+                let mut bids = summary.bids.clone();
+                bids.sort_by_key(|level| std::cmp::Reverse(price(level)));
+                assert_eq!(bids, book.bids());
+                assert_eq!(summary.asks, book.asks());
+                deltas.push(delta);
+            }
+        }
+        assert_eq!(3, deltas.len());
+        assert!(deltas[0].snapshot);
+        assert_eq!(
+            vec![
+                LevelChange {
+                    change: ChangeType::Removed.into(),
+                    level: Some(level("b", "9", "2")),
+                },
+                LevelChange {
+                    change: ChangeType::Updated.into(),
+                    level: Some(level("a", "10", "3")),
+                },
+                LevelChange {
+                    change: ChangeType::Inserted.into(),
+                    level: Some(level("b", "9.5", "1")),
+                },
+            ],
+            deltas[1].bids
+        );
+        assert!(deltas[1].asks.is_empty());
+        assert_eq!(2, deltas[2].sequence);
+    }
+
+    #[test]
+    fn test_gap_detection() {
+        let mut encoder = DeltaEncoder::default();
+        let first = encoder.encode(&summary(vec![level("a", "10", "1")], vec![]));
+        let second = encoder.encode(&summary(vec![level("a", "10", "2")], vec![]));
+        let third = encoder.encode(&summary(vec![level("a", "10", "3")], vec![]));
+        let mut book = DeltaBook::default();
+        assert_eq!(
+            Err(DeltaError::MissingSnapshot),
+            book.apply(second.as_ref().unwrap())
+        );
+        book.apply(first.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            Err(DeltaError::Gap {
+                expected: 1,
+                received: 2
+            }),
+            book.apply(third.as_ref().unwrap())
+        );
+        assert_eq!(vec![level("a", "10", "1")], book.bids());
+    }
+}
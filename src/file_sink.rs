@@ -0,0 +1,522 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use log::{error, info};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use rust_decimal::Decimal;
+use tokio::sync::{mpsc, watch};
+use tokio::{task, time};
+
+use crate::common::unix_millis;
+use crate::configuration::{FileSinkConfig, SinkFormat};
+use crate::decimal::parse_or;
+use crate::orderbook::{self, Summary};
+
+const FILE_PREFIX: &str = "summaries";
+
+/// Rows waiting to be written when the disk is slower than the summaries.
+const ROWS_CHANNEL_SIZE: usize = 1024;
+
+/// Writes every summary, or the latest one every `interval` when set, to the daily files. The files
+/// are written on a blocking thread, so a slow disk doesn't stall the runtime.
+pub async fn file_sink(
+    summaries: watch::Receiver<Summary>,
+    config: FileSinkConfig,
+    levels: usize,
+) -> anyhow::Result<()> {
+    let interval = config.interval;
+    let (sender, receiver) = mpsc::channel(ROWS_CHANNEL_SIZE);
+    let writer = task::spawn_blocking(move || write_rows(receiver, &config, levels));
+    send_rows(summaries, interval, levels, sender).await;
+    // The writer stops once the rows are written, or earlier on error.
+    writer.await.context("file writer panicked")??;
+    info!("summaries channel closed. Exiting");
+    Ok(())
+}
+
+/// Sends the rows to write until the summaries channel is closed or the writer stops.
+async fn send_rows(
+    mut summaries: watch::Receiver<Summary>,
+    interval: Option<Duration>,
+    levels: usize,
+    sender: mpsc::Sender<Row>,
+) {
+    match interval {
+        None => {
+            while summaries.changed().await.is_ok() {
+                let row = Row::new(&summaries.borrow_and_update(), SystemTime::now(), levels);
+                if sender.send(row).await.is_err() {
+                    return;
+                }
+            }
+        }
+        Some(interval) => {
+            // Nothing is written before the first summary is published.
+            if summaries.changed().await.is_ok() {
+                let mut ticker = time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    if summaries.has_changed().is_err() {
+                        break;
+                    }
+                    let row = Row::new(&summaries.borrow_and_update(), SystemTime::now(), levels);
+                    if sender.send(row).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_rows(
+    mut rows: mpsc::Receiver<Row>,
+    config: &FileSinkConfig,
+    levels: usize,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(&config.directory)
+        .with_context(|| format!("creating {}", config.directory.display()))?;
+    let mut files = DailyFiles::new(config, levels);
+    while let Some(row) = rows.blocking_recv() {
+        files.write(&row)?;
+    }
+    Ok(())
+}
+
+/// A summary flattened into `levels` bids and asks, missing levels being `None`.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    timestamp: SystemTime,
+    spread: Decimal,
+    bids: Vec<Option<Level>>,
+    asks: Vec<Option<Level>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Level {
+    exchange: String,
+    price: Decimal,
+    amount: Decimal,
+}
+
+impl Row {
+    fn new(summary: &Summary, timestamp: SystemTime, levels: usize) -> Self {
+        let side = |side: &[orderbook::Level]| {
+            (0..levels)
+                .map(|i| {
+                    side.get(i).map(|level| Level {
+                        exchange: level.exchange.clone(),
+                        price: parse_or(&level.price_decimal, level.price),
+                        amount: parse_or(&level.amount_decimal, level.amount),
+                    })
+                })
+                .collect()
+        };
+        Self {
+            timestamp,
+            spread: parse_or(&summary.spread_decimal, summary.spread),
+            bids: side(&summary.bids),
+            asks: side(&summary.asks),
+        }
+    }
+
+    /// Column names, the levels of each side being flattened into exchange, price and amount.
+    fn header(levels: usize) -> Vec<String> {
+        let mut header = vec![
+            "timestamp_ms".to_owned(),
+            "timestamp".to_owned(),
+            "spread".to_owned(),
+        ];
+        for side in ["bid", "ask"] {
+            for level in 1..=levels {
+                for field in ["exchange", "price", "amount"] {
+                    header.push(format!("{side}_{level}_{field}"));
+                }
+            }
+        }
+        header
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        let mut record = vec![
+            unix_millis(self.timestamp).to_string(),
+            humantime::format_rfc3339_millis(self.timestamp).to_string(),
+            self.spread.to_string(),
+        ];
+        for level in self.bids.iter().chain(&self.asks) {
+            match level {
+                Some(level) => record.extend([
+                    level.exchange.clone(),
+                    level.price.to_string(),
+                    level.amount.to_string(),
+                ]),
+                None => record.extend([String::new(), String::new(), String::new()]),
+            }
+        }
+        record
+    }
+}
+
+/// UTC date of `time`, as `YYYY-MM-DD`.
+fn utc_date(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_owned()
+}
+
+trait RowWriter: Send {
+    fn write(&mut self, row: &Row) -> anyhow::Result<()>;
+}
+
+/// Files of the current day in every configured format, rotated at midnight UTC.
+struct DailyFiles {
+    directory: PathBuf,
+    formats: Vec<SinkFormat>,
+    levels: usize,
+    row_group_size: usize,
+    date: String,
+    writers: Vec<Box<dyn RowWriter>>,
+}
+
+impl DailyFiles {
+    fn new(config: &FileSinkConfig, levels: usize) -> Self {
+        Self {
+            directory: config.directory.clone(),
+            formats: config.formats.clone(),
+            levels,
+            row_group_size: config.row_group_size.max(1),
+            date: String::new(),
+            writers: vec![],
+        }
+    }
+
+    fn write(&mut self, row: &Row) -> anyhow::Result<()> {
+        let date = utc_date(row.timestamp);
+        if date != self.date {
+            // Dropping the writers closes the files of the previous day.
+            self.writers.clear();
+            for format in &self.formats {
+                let writer: Box<dyn RowWriter> = match format {
+                    SinkFormat::Csv => Box::new(CsvFile::open(
+                        &self.directory.join(format!("{FILE_PREFIX}-{date}.csv")),
+                        self.levels,
+                    )?),
+                    SinkFormat::Parquet => Box::new(ParquetFile::create(
+                        &parquet_path(&self.directory, &date),
+                        self.levels,
+                        self.row_group_size,
+                    )?),
+                };
+                self.writers.push(writer);
+            }
+            self.date = date;
+        }
+        for writer in &mut self.writers {
+            writer.write(row)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Parquet file can't be appended to, so a restart within a day gets a new numbered file.
+fn parquet_path(directory: &Path, date: &str) -> PathBuf {
+    let mut path = directory.join(format!("{FILE_PREFIX}-{date}.parquet"));
+    let mut number = 1;
+    while path.exists() {
+        path = directory.join(format!("{FILE_PREFIX}-{date}.{number}.parquet"));
+        number += 1;
+    }
+    path
+}
+
+/// Rows appended to a CSV file and flushed one by one.
+struct CsvFile {
+    writer: csv::Writer<File>,
+}
+
+impl CsvFile {
+    fn open(path: &Path, levels: usize) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = csv::Writer::from_writer(file);
+        if is_empty {
+            writer.write_record(Row::header(levels))?;
+        }
+        info!("Writing summaries to {}", path.display());
+        Ok(Self { writer })
+    }
+}
+
+impl RowWriter for CsvFile {
+    fn write(&mut self, row: &Row) -> anyhow::Result<()> {
+        self.writer.write_record(row.to_record())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Rows buffered into row groups of a Parquet file, which is only readable once closed.
+struct ParquetFile {
+    path: PathBuf,
+    writer: Option<SerializedFileWriter<File>>,
+    levels: usize,
+    row_group_size: usize,
+    rows: Vec<Row>,
+}
+
+impl ParquetFile {
+    fn create(path: &Path, levels: usize, row_group_size: usize) -> anyhow::Result<Self> {
+        let schema = Arc::new(parse_message_type(&parquet_schema(levels))?);
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let properties = Arc::new(WriterProperties::builder().build());
+        info!("Writing summaries to {}", path.display());
+        Ok(Self {
+            path: path.to_owned(),
+            writer: Some(SerializedFileWriter::new(file, schema, properties)?),
+            levels,
+            row_group_size,
+            rows: Vec::with_capacity(row_group_size),
+        })
+    }
+
+    fn write_row_group(&mut self) -> anyhow::Result<()> {
+        let (Some(writer), false) = (self.writer.as_mut(), self.rows.is_empty()) else {
+            return Ok(());
+        };
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            write_column(&mut column, index, &self.rows, self.levels)?;
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.write_row_group()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl RowWriter for ParquetFile {
+    fn write(&mut self, row: &Row) -> anyhow::Result<()> {
+        self.rows.push(row.clone());
+        if self.rows.len() >= self.row_group_size {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ParquetFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("Error closing {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Same columns as the CSV files, without the formatted timestamp. The spread, prices and amounts
+/// are exact decimal strings, as their scale depends on the symbol.
+fn parquet_schema(levels: usize) -> String {
+    let mut schema = String::from(
+        "message summary {\n  REQUIRED INT64 timestamp_ms (TIMESTAMP(MILLIS,true));\n  REQUIRED BYTE_ARRAY spread (UTF8);\n",
+    );
+    for side in ["bid", "ask"] {
+        for level in 1..=levels {
+            for field in ["exchange", "price", "amount"] {
+                schema.push_str(&format!(
+                    "  OPTIONAL BYTE_ARRAY {side}_{level}_{field} (UTF8);\n"
+                ));
+            }
+        }
+    }
+    schema.push('}');
+    schema
+}
+
+/// Writes the values of the `index`-th column of the schema.
+fn write_column(
+    column: &mut SerializedColumnWriter<'_>,
+    index: usize,
+    rows: &[Row],
+    levels: usize,
+) -> anyhow::Result<()> {
+    match index {
+        0 => {
+            let timestamps: Vec<i64> = rows
+                .iter()
+                .map(|row| unix_millis(row.timestamp) as i64)
+                .collect();
+            column
+                .typed::<Int64Type>()
+                .write_batch(&timestamps, None, None)?;
+        }
+        1 => {
+            let spreads: Vec<ByteArray> = rows
+                .iter()
+                .map(|row| ByteArray::from(row.spread.to_string().into_bytes()))
+                .collect();
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(&spreads, None, None)?;
+        }
+        index => {
+            let index = index - 2;
+            let (is_bid, level, field) = (index < levels * 3, index / 3 % levels, index % 3);
+            let levels: Vec<Option<&Level>> = rows
+                .iter()
+                .map(|row| {
+                    let side = if is_bid { &row.bids } else { &row.asks };
+                    side[level].as_ref()
+                })
+                .collect();
+            let definitions: Vec<i16> = levels.iter().map(|level| level.is_some() as i16).collect();
+            let values: Vec<ByteArray> = levels
+                .iter()
+                .flatten()
+                .map(|level| {
+                    let value = match field {
+                        0 => level.exchange.clone(),
+                        1 => level.price.to_string(),
+                        _ => level.amount.to_string(),
+                    };
+                    ByteArray::from(value.into_bytes())
+                })
+                .collect();
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(&values, Some(&definitions), None)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    use super::*;
+
+    fn summary() -> Summary {
+        let level = |exchange: &str, price: &str| orderbook::Level {
+            exchange: exchange.to_owned(),
+            price_decimal: price.to_owned(),
+            amount_decimal: "1.5".to_owned(),
+            ..Default::default()
+        };
+        Summary {
+            spread_decimal: "1".to_owned(),
+            bids: vec![level("a", "99"), level("b", "98")],
+            asks: vec![level("b", "100")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_to_record() {
+        let at = UNIX_EPOCH + Duration::from_millis(86_400_123);
+        let row = Row::new(&summary(), at, 2);
+        assert_eq!(Row::header(2).len(), row.to_record().len());
+        assert_eq!(
+            vec![
+                "86400123",
+                "1970-01-02T00:00:00.123Z",
+                "1",
+                "a",
+                "99",
+                "1.5",
+                "b",
+                "98",
+                "1.5",
+                "b",
+                "100",
+                "1.5",
+                "",
+                "",
+                ""
+            ],
+            row.to_record()
+        );
+        assert_eq!("1970-01-02", utc_date(at));
+    }
+
+    #[test]
+    fn test_daily_files() {
+        let directory = std::env::temp_dir().join(format!("file-sink-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let config = FileSinkConfig {
+            directory: directory.clone(),
+            formats: vec![SinkFormat::Csv, SinkFormat::Parquet],
+            interval: None,
+            row_group_size: 2,
+        };
+        let day = |day: u64| UNIX_EPOCH + Duration::from_secs(day * 86_400);
+        let mut files = DailyFiles::new(&config, 3);
+        for at in [day(0), day(0), day(0), day(1)] {
+            files.write(&Row::new(&summary(), at, 3)).unwrap();
+        }
+        drop(files);
+
+        let csv = fs::read_to_string(directory.join("summaries-1970-01-01.csv")).unwrap();
+        assert_eq!(4, csv.lines().count());
+        let parquet = File::open(directory.join("summaries-1970-01-01.parquet")).unwrap();
+        let metadata = SerializedFileReader::new(parquet)
+            .unwrap()
+            .metadata()
+            .clone();
+        assert_eq!(3, metadata.file_metadata().num_rows());
+        assert_eq!(2, metadata.num_row_groups());
+        assert_eq!(20, metadata.file_metadata().schema_descr().num_columns());
+        let parquet = File::open(directory.join("summaries-1970-01-01.parquet")).unwrap();
+        let row = SerializedFileReader::new(parquet)
+            .unwrap()
+            .get_row_iter(None)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!("99", row.get_string(3).unwrap());
+        assert_eq!("1.5", row.get_string(4).unwrap());
+        assert!(directory.join("summaries-1970-01-02.parquet").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_sink() {
+        let directory = std::env::temp_dir().join(format!("file-sink-task-{}", std::process::id()));
+        let config = FileSinkConfig {
+            directory: directory.clone(),
+            formats: vec![SinkFormat::Csv],
+            interval: None,
+            row_group_size: 1,
+        };
+        let (sender, summaries) = watch::channel(Summary::default());
+        let task = tokio::spawn(file_sink(summaries, config, 1));
+        sender.send(summary()).unwrap();
+        drop(sender);
+        task.await.unwrap().unwrap();
+
+        let path = directory.join(format!("summaries-{}.csv", utc_date(SystemTime::now())));
+        assert_eq!(2, fs::read_to_string(path).unwrap().lines().count());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
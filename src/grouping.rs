@@ -0,0 +1,268 @@
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::aggregator::PriceLevel;
+use crate::configuration::ScaleConfig;
+use crate::decimal::parse_or;
+use crate::orderbook::{self, grouping::Bucket, ConsolidatedLevel, Summary};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BucketSize {
+    Tick(Decimal),
+    PercentFromMid(Decimal),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum GroupingError {
+    #[error("grouping bucket not set")]
+    MissingBucket,
+    #[error("grouping bucket size must be positive, got {0}")]
+    InvalidBucketSize(f64),
+    #[error("grouping bucket size {0} is out of the supported range")]
+    UnsupportedBucketSize(f64),
+    #[error("grouping bucket of {0} rounds to zero at the price scale")]
+    BucketRoundsToZero(Decimal),
+    #[error("grouping prices by {0} overflows")]
+    Overflow(Decimal),
+}
+
+impl TryFrom<orderbook::Grouping> for BucketSize {
+    type Error = GroupingError;
+
+    fn try_from(value: orderbook::Grouping) -> Result<Self, Self::Error> {
+        let (size, bucket_size): (f64, fn(Decimal) -> BucketSize) =
+            match value.bucket.ok_or(GroupingError::MissingBucket)? {
+                Bucket::TickSize(tick_size) => (tick_size, BucketSize::Tick),
+                Bucket::PercentFromMid(percent) => (percent, BucketSize::PercentFromMid),
+            };
+        if size.is_nan() || size <= 0.0 {
+            return Err(GroupingError::InvalidBucketSize(size));
+        }
+        match Decimal::try_from(size) {
+            Ok(decimal) if size.is_finite() && decimal > Decimal::ZERO => Ok(bucket_size(decimal)),
+            _ => Err(GroupingError::UnsupportedBucketSize(size)),
+        }
+    }
+}
+
+/// Fills `grouped_bids` and `grouped_asks` summing the summary levels into price buckets. Bids are
+/// rounded down and asks up to the bucket boundary, so a bucket never looks better than its levels.
+/// The last bucket of a truncated side is marked `partial`, as the book goes on past its levels.
+pub fn group_summary(summary: &mut Summary, bucket_size: BucketSize) -> Result<(), GroupingError> {
+    let scale = ScaleConfig {
+        price: summary.price_scale,
+        quantity: summary.quantity_scale,
+    };
+    let tick = match bucket_size {
+        BucketSize::Tick(tick) => tick,
+        BucketSize::PercentFromMid(percent) => match mid_price(summary) {
+            Some(mid) => mid
+                .checked_mul(percent)
+                .ok_or(GroupingError::Overflow(percent))?
+                .checked_div(Decimal::ONE_HUNDRED)
+                .ok_or(GroupingError::Overflow(percent))?
+                .round_dp(scale.price),
+            None => return Ok(()),
+        },
+    };
+    if tick <= Decimal::ZERO {
+        return Err(GroupingError::BucketRoundsToZero(tick));
+    }
+    summary.grouped_bids = group_levels(&summary.bids, tick, &scale, Decimal::floor)?;
+    summary.grouped_asks = group_levels(&summary.asks, tick, &scale, Decimal::ceil)?;
+    mark_partial(&mut summary.grouped_bids, summary.bids_truncated);
+    mark_partial(&mut summary.grouped_asks, summary.asks_truncated);
+    Ok(())
+}
+
+fn mark_partial(buckets: &mut [ConsolidatedLevel], truncated: bool) {
+    if let Some(last) = buckets.last_mut() {
+        last.partial = truncated;
+    }
+}
+
+fn level_price(level: &orderbook::Level) -> Decimal {
+    parse_or(&level.price_decimal, level.price)
+}
+
+fn mid_price(summary: &Summary) -> Option<Decimal> {
+    match (summary.bids.first(), summary.asks.first()) {
+        (Some(bid), Some(ask)) => Some((level_price(bid) + level_price(ask)) / Decimal::TWO),
+        (Some(level), None) | (None, Some(level)) => Some(level_price(level)),
+        (None, None) => None,
+    }
+}
+
+fn group_levels(
+    levels: &[orderbook::Level],
+    tick: Decimal,
+    scale: &ScaleConfig,
+    round: impl Fn(&Decimal) -> Decimal,
+) -> Result<Vec<ConsolidatedLevel>, GroupingError> {
+    let mut result: Vec<PriceLevel> = vec![];
+    for level in levels {
+        let price = level_price(level)
+            .checked_div(tick)
+            .map(|buckets| round(&buckets))
+            .and_then(|buckets| buckets.checked_mul(tick))
+            .ok_or(GroupingError::Overflow(tick))?;
+        let amount = parse_or(&level.amount_decimal, level.amount);
+        match result.last_mut() {
+            Some(last) if last.price == price => last.add_amount(&level.exchange, amount),
+            _ => {
+                let mut bucket = PriceLevel::new(price);
+                bucket.add_amount(&level.exchange, amount);
+                result.push(bucket);
+            }
+        }
+    }
+    Ok(result.iter().map(|bucket| bucket.to_proto(scale)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> orderbook::Level {
+        orderbook::Level {
+            exchange: exchange.to_owned(),
+            price,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            spread: 0.5,
+            bids: vec![
+                level("a", 10.0, 1.0),
+                level("b", 9.75, 2.0),
+                level("a", 9.5, 1.0),
+                level("b", 8.25, 1.0),
+            ],
+            asks: vec![
+                level("b", 10.5, 1.0),
+                level("a", 10.75, 1.0),
+                level("a", 11.0, 2.0),
+                level("b", 12.25, 1.0),
+            ],
+            price_scale: 4,
+            quantity_scale: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_grouping_by_tick() {
+        let mut summary = summary();
+        group_summary(&mut summary, BucketSize::Tick(Decimal::ONE)).unwrap();
+        let prices_and_amounts = |levels: &[ConsolidatedLevel]| -> Vec<(f64, f64)> {
+            levels.iter().map(|l| (l.price, l.amount)).collect()
+        };
+        assert_eq!(
+            vec![(10.0, 1.0), (9.0, 3.0), (8.0, 1.0)],
+            prices_and_amounts(&summary.grouped_bids)
+        );
+        assert_eq!(
+            vec![(11.0, 4.0), (13.0, 1.0)],
+            prices_and_amounts(&summary.grouped_asks)
+        );
+        assert_eq!(2, summary.grouped_bids[1].exchanges.len());
+        assert!(summary.grouped_bids.iter().all(|level| !level.partial));
+    }
+
+    #[test]
+    fn test_grouping_truncated_book() {
+        let mut summary = Summary {
+            asks_truncated: true,
+            ..summary()
+        };
+        group_summary(&mut summary, BucketSize::Tick(Decimal::ONE)).unwrap();
+        let partial = |levels: &[ConsolidatedLevel]| -> Vec<bool> {
+            levels.iter().map(|l| l.partial).collect()
+        };
+        assert_eq!(vec![false, true], partial(&summary.grouped_asks));
+        assert_eq!(vec![false, false, false], partial(&summary.grouped_bids));
+    }
+
+    #[test]
+    fn test_grouping_by_percent_from_mid() {
+        let mut summary = summary();
+        // mid is 10.25, so 10% from mid gives buckets of 1.025
+        group_summary(&mut summary, BucketSize::PercentFromMid(Decimal::TEN)).unwrap();
+        let amounts = |levels: &[ConsolidatedLevel]| -> Vec<f64> {
+            levels.iter().map(|l| l.amount).collect()
+        };
+        assert_eq!(vec![4.0, 1.0], amounts(&summary.grouped_bids));
+        assert_eq!(vec![4.0, 1.0], amounts(&summary.grouped_asks));
+    }
+
+    #[test]
+    fn test_grouping_exact_decimals() {
+        let decimal_level = |price: &str| orderbook::Level {
+            price_decimal: price.to_owned(),
+            amount_decimal: "0.10".to_owned(),
+            ..level("a", 0.0, 0.0)
+        };
+        let mut summary = Summary {
+            bids: vec![decimal_level("0.06520"), decimal_level("0.06519")],
+            price_scale: 5,
+            quantity_scale: 2,
+            ..Default::default()
+        };
+        group_summary(&mut summary, BucketSize::Tick(Decimal::new(1, 4))).unwrap();
+        let buckets: Vec<_> = summary
+            .grouped_bids
+            .iter()
+            .map(|l| (l.price_decimal.as_str(), l.amount_decimal.as_str()))
+            .collect();
+        assert_eq!(vec![("0.06520", "0.10"), ("0.06510", "0.10")], buckets);
+    }
+
+    #[test]
+    fn test_invalid_grouping() {
+        let grouping = orderbook::Grouping {
+            bucket: Some(Bucket::TickSize(0.0)),
+        };
+        assert_eq!(
+            Err(GroupingError::InvalidBucketSize(0.0)),
+            BucketSize::try_from(grouping)
+        );
+        for tick_size in [f64::INFINITY, 1e30, 1e-30] {
+            let grouping = orderbook::Grouping {
+                bucket: Some(Bucket::TickSize(tick_size)),
+            };
+            assert_eq!(
+                Err(GroupingError::UnsupportedBucketSize(tick_size)),
+                BucketSize::try_from(grouping)
+            );
+        }
+    }
+
+    #[test]
+    fn test_grouping_overflow() {
+        let tick = BucketSize::try_from(orderbook::Grouping {
+            bucket: Some(Bucket::TickSize(1e-28)),
+        })
+        .unwrap();
+        assert!(matches!(
+            group_summary(&mut summary(), tick),
+            Err(GroupingError::Overflow(_))
+        ));
+        let percent = BucketSize::try_from(orderbook::Grouping {
+            bucket: Some(Bucket::PercentFromMid(1e28)),
+        })
+        .unwrap();
+        assert!(matches!(
+            group_summary(&mut summary(), percent),
+            Err(GroupingError::Overflow(_))
+        ));
+        let rounded = BucketSize::PercentFromMid(Decimal::new(1, 6));
+        assert!(matches!(
+            group_summary(&mut summary(), rounded),
+            Err(GroupingError::BucketRoundsToZero(_))
+        ));
+    }
+}
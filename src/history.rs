@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::info;
+use tokio::sync::watch;
+
+use crate::common::unix_millis;
+use crate::configuration::HistoryConfig;
+use crate::decimal::{parse_or, to_f64};
+use crate::orderbook::{self, Resolution, Summary};
+
+const RESOLUTIONS: [Resolution; 3] = [Resolution::Second, Resolution::Minute, Resolution::Hour];
+
+/// Records every summary into the history.
+pub async fn history_recorder(
+    mut summaries: watch::Receiver<Summary>,
+    history: Arc<Mutex<History>>,
+) -> anyhow::Result<()> {
+    while summaries.changed().await.is_ok() {
+        let summary = summaries.borrow_and_update().clone();
+        history
+            .lock()
+            .expect("history lock poisoned")
+            .record(&summary, SystemTime::now());
+    }
+    info!("summaries channel closed. Exiting");
+    Ok(())
+}
+
+/// Bars of the aggregated book at every resolution, keeping a bounded number of each.
+#[derive(Debug)]
+pub struct History {
+    series: [BarSeries; 3],
+}
+
+#[derive(Debug)]
+struct BarSeries {
+    resolution_ms: u64,
+    capacity: usize,
+    bars: VecDeque<Bar>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bar {
+    start_ms: u64,
+    samples: u32,
+    mid: Option<orderbook::Ohlc>,
+    spread: Option<orderbook::Ohlc>,
+    best_bid: Option<orderbook::Ohlc>,
+    best_ask: Option<orderbook::Ohlc>,
+    /// Sums of the bid and ask amounts by exchange.
+    depths: BTreeMap<String, (f64, f64)>,
+}
+
+/// Top of the book and amount by exchange of a summary.
+#[derive(Debug, Default)]
+struct Sample {
+    best_bid: Option<f64>,
+    best_ask: Option<f64>,
+    depths: BTreeMap<String, (f64, f64)>,
+}
+
+impl History {
+    pub fn new(config: &HistoryConfig) -> Self {
+        let series = |resolution: Resolution, capacity: usize| BarSeries {
+            resolution_ms: resolution_ms(resolution),
+            capacity,
+            bars: VecDeque::new(),
+        };
+        Self {
+            series: [
+                series(Resolution::Second, config.second_bars),
+                series(Resolution::Minute, config.minute_bars),
+                series(Resolution::Hour, config.hour_bars),
+            ],
+        }
+    }
+
+    pub fn record(&mut self, summary: &Summary, now: SystemTime) {
+        let sample = Sample::from_summary(summary);
+        let now_ms = unix_millis(now);
+        for series in &mut self.series {
+            series.record(&sample, now_ms);
+        }
+    }
+
+    /// Bars starting at or after `since_ms`, the latest `limit` ones when it is not zero. The
+    /// last bar may still be in progress.
+    pub fn bars(&self, resolution: Resolution, since_ms: u64, limit: usize) -> Vec<orderbook::Bar> {
+        let index = RESOLUTIONS
+            .iter()
+            .position(|r| *r == resolution)
+            .expect("every resolution has a series");
+        let bars: Vec<_> = self.series[index]
+            .bars
+            .iter()
+            .filter(|bar| bar.start_ms >= since_ms)
+            .collect();
+        let skip = if limit == 0 {
+            0
+        } else {
+            bars.len().saturating_sub(limit)
+        };
+        bars[skip..].iter().map(|bar| bar.to_proto()).collect()
+    }
+}
+
+impl BarSeries {
+    fn record(&mut self, sample: &Sample, now_ms: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let start_ms = now_ms - now_ms % self.resolution_ms;
+        if self.bars.back().is_none_or(|bar| bar.start_ms < start_ms) {
+            if self.bars.len() == self.capacity {
+                self.bars.pop_front();
+            }
+            self.bars.push_back(Bar {
+                start_ms,
+                ..Default::default()
+            });
+        }
+        if let Some(bar) = self.bars.back_mut() {
+            bar.add(sample);
+        }
+    }
+}
+
+impl Bar {
+    fn add(&mut self, sample: &Sample) {
+        self.samples += 1;
+        update_ohlc(&mut self.best_bid, sample.best_bid);
+        update_ohlc(&mut self.best_ask, sample.best_ask);
+        if let (Some(best_bid), Some(best_ask)) = (sample.best_bid, sample.best_ask) {
+            update_ohlc(&mut self.mid, Some((best_bid + best_ask) / 2.0));
+            update_ohlc(&mut self.spread, Some(best_ask - best_bid));
+        }
+        for (exchange, (bid_amount, ask_amount)) in &sample.depths {
+            let depth = self.depths.entry(exchange.clone()).or_default();
+            depth.0 += bid_amount;
+            depth.1 += ask_amount;
+        }
+    }
+
+    fn to_proto(&self) -> orderbook::Bar {
+        let samples = f64::from(self.samples.max(1));
+        orderbook::Bar {
+            start_ms: self.start_ms,
+            samples: self.samples,
+            mid: self.mid.clone(),
+            spread: self.spread.clone(),
+            best_bid: self.best_bid.clone(),
+            best_ask: self.best_ask.clone(),
+            depths: self
+                .depths
+                .iter()
+                .map(|(exchange, (bid_amount, ask_amount))| orderbook::DepthBar {
+                    exchange: exchange.clone(),
+                    bid_amount: bid_amount / samples,
+                    ask_amount: ask_amount / samples,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Sample {
+    fn from_summary(summary: &Summary) -> Self {
+        let price = |level: &orderbook::Level| to_f64(parse_or(&level.price_decimal, level.price));
+        let amount =
+            |level: &orderbook::Level| to_f64(parse_or(&level.amount_decimal, level.amount));
+        let mut depths: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+        for bid in &summary.bids {
+            depths.entry(bid.exchange.clone()).or_default().0 += amount(bid);
+        }
+        for ask in &summary.asks {
+            depths.entry(ask.exchange.clone()).or_default().1 += amount(ask);
+        }
+        Self {
+            best_bid: summary.bids.first().map(price),
+            best_ask: summary.asks.first().map(price),
+            depths,
+        }
+    }
+}
+
+fn update_ohlc(ohlc: &mut Option<orderbook::Ohlc>, value: Option<f64>) {
+    let Some(value) = value else {
+        return;
+    };
+    match ohlc {
+        Some(ohlc) => {
+            ohlc.high = ohlc.high.max(value);
+            ohlc.low = ohlc.low.min(value);
+            ohlc.close = value;
+        }
+        None => {
+            *ohlc = Some(orderbook::Ohlc {
+                open: value,
+                high: value,
+                low: value,
+                close: value,
+            })
+        }
+    }
+}
+
+fn resolution_ms(resolution: Resolution) -> u64 {
+    match resolution {
+        Resolution::Second => 1_000,
+        Resolution::Minute => 60_000,
+        Resolution::Hour => 3_600_000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn summary(best_bid: f64, best_ask: f64) -> Summary {
+        let level = |exchange: &str, price: f64| orderbook::Level {
+            exchange: exchange.to_owned(),
+            price,
+            amount: 1.0,
+            ..Default::default()
+        };
+        Summary {
+            bids: vec![level("a", best_bid), level("b", best_bid - 1.0)],
+            asks: vec![level("b", best_ask)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_bars() {
+        let mut history = History::new(&HistoryConfig {
+            second_bars: 2,
+            minute_bars: 10,
+            hour_bars: 0,
+        });
+        let at = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+        history.record(&summary(10.0, 12.0), at(60_100));
+        history.record(&summary(11.0, 12.0), at(60_900));
+        history.record(&summary(9.0, 13.0), at(61_500));
+        history.record(&summary(10.0, 11.0), at(62_000));
+
+        let seconds = history.bars(Resolution::Second, 0, 0);
+        assert_eq!(
+            vec![61_000, 62_000],
+            seconds.iter().map(|bar| bar.start_ms).collect::<Vec<_>>()
+        );
+        let minutes = history.bars(Resolution::Minute, 0, 0);
+        assert_eq!(1, minutes.len());
+        let minute = &minutes[0];
+        assert_eq!(60_000, minute.start_ms);
+        assert_eq!(4, minute.samples);
+        assert_eq!(
+            Some(orderbook::Ohlc {
+                open: 11.0,
+                high: 11.5,
+                low: 10.5,
+                close: 10.5,
+            }),
+            minute.mid
+        );
+        assert_eq!(
+            Some(orderbook::Ohlc {
+                open: 2.0,
+                high: 4.0,
+                low: 1.0,
+                close: 1.0,
+            }),
+            minute.spread
+        );
+        assert_eq!(
+            vec![
+                orderbook::DepthBar {
+                    exchange: "a".to_owned(),
+                    bid_amount: 1.0,
+                    ask_amount: 0.0,
+                },
+                orderbook::DepthBar {
+                    exchange: "b".to_owned(),
+                    bid_amount: 1.0,
+                    ask_amount: 1.0,
+                },
+            ],
+            minute.depths
+        );
+        assert!(history.bars(Resolution::Hour, 0, 0).is_empty());
+        assert_eq!(1, history.bars(Resolution::Second, 0, 1).len());
+        assert_eq!(1, history.bars(Resolution::Second, 61_001, 0).len());
+    }
+}
@@ -0,0 +1,166 @@
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::{Stream, StreamExt};
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+
+use crate::orderbook::Summary;
+use crate::server::snapshot_stream;
+use crate::symbols::SymbolAliases;
+use crate::websocket::truncate;
+
+#[derive(Clone)]
+struct ApiState {
+    summaries: watch::Receiver<Summary>,
+    symbol: Arc<SymbolAliases>,
+}
+
+/// Options of both endpoints, as query parameters.
+#[derive(Debug, Deserialize, Default)]
+struct BookQuery {
+    /// Levels sent on each side, all of them when not set.
+    depth: Option<usize>,
+    /// Smallest time between two streamed summaries.
+    #[serde(default)]
+    min_interval_ms: u64,
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// Serves `GET /book/{symbol}`, the current summary as JSON, and `GET /book/{symbol}/stream`,
+/// the summaries as Server-Sent Events. `symbol` is any of the aliases of the aggregated symbol,
+/// `BASE-QUOTE` standing for the canonical form as the slash would split the path.
+pub async fn http_api(
+    listener: TcpListener,
+    summaries: watch::Receiver<Summary>,
+    symbol: SymbolAliases,
+) -> anyhow::Result<()> {
+    info!("HTTP API listening on {}", listener.local_addr()?);
+    let state = ApiState {
+        summaries,
+        symbol: symbol.into(),
+    };
+    let app = Router::new()
+        .route("/book/:symbol", get(book))
+        .route("/book/:symbol/stream", get(book_stream))
+        .with_state(state);
+    axum::Server::from_tcp(listener)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn book(
+    State(state): State<ApiState>,
+    Path(symbol): Path<String>,
+    Query(query): Query<BookQuery>,
+) -> Result<Json<Summary>, ApiError> {
+    check_symbol(&state, &symbol)?;
+    let mut summary = state.summaries.borrow().clone();
+    if let Some(depth) = query.depth {
+        truncate(&mut summary, depth);
+    }
+    Ok(Json(summary))
+}
+
+async fn book_stream(
+    State(state): State<ApiState>,
+    Path(symbol): Path<String>,
+    Query(query): Query<BookQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    check_symbol(&state, &symbol)?;
+    let min_interval = Duration::from_millis(query.min_interval_ms);
+    let events = snapshot_stream(state.summaries, min_interval).filter_map(move |mut summary| {
+        if let Some(depth) = query.depth {
+            truncate(&mut summary, depth);
+        }
+        let event = Event::default().event("summary").json_data(summary);
+        futures::future::ready(event.ok().map(Ok))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn check_symbol(state: &ApiState, symbol: &str) -> Result<(), ApiError> {
+    if state.symbol.matches(symbol) {
+        Ok(())
+    } else {
+        Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!(
+                "unknown symbol {symbol}, only {} is aggregated",
+                state.symbol.symbol()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::symbols::SymbolRules;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (_sender, summaries) = watch::channel(Summary {
+            spread: 1.5,
+            ..Default::default()
+        });
+        let symbol = SymbolAliases::new("ethbtc".to_owned(), vec![SymbolRules::concatenated()]);
+        tokio::spawn(http_api(listener, summaries, symbol));
+
+        let client = reqwest::Client::new();
+        let book: serde_json::Value = client
+            .get(format!("http://{address}/book/ETHBTC"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(1.5, book["spread"]);
+        for alias in ["ETH-BTC", "ETH%2FBTC"] {
+            let response = client
+                .get(format!("http://{address}/book/{alias}"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK.as_u16(), response.status().as_u16());
+        }
+        let unknown = client
+            .get(format!("http://{address}/book/ltcbtc"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND.as_u16(), unknown.status().as_u16());
+
+        let mut stream = client
+            .get(format!("http://{address}/book/ethbtc/stream"))
+            .send()
+            .await
+            .unwrap();
+        let chunk = stream.chunk().await.unwrap().unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(event.starts_with("event:summary\ndata:{\"spread\":1.5"));
+    }
+}
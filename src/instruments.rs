@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Context;
+use log::info;
+use rust_decimal::Decimal;
+
+use crate::configuration::{AppConfig, InstrumentConfig, ScaleConfig};
+use crate::symbols::SymbolRules;
+use crate::{binance, bitstamp, orderbook};
+
+/// Instrument metadata by exchange and exchange symbol.
+#[derive(Debug, Default, Clone)]
+pub struct InstrumentRegistry {
+    instruments: BTreeMap<(String, String), InstrumentConfig>,
+    symbol_rules: HashMap<String, SymbolRules>,
+}
+
+impl InstrumentRegistry {
+    /// An empty registry resolving symbols with the `symbol_rules` of each exchange.
+    pub fn new(symbol_rules: HashMap<String, SymbolRules>) -> Self {
+        Self {
+            instruments: BTreeMap::new(),
+            symbol_rules,
+        }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        let mut registry = Self::new(config.exchange_symbol_rules());
+        for (exchange, symbols) in &config.instruments {
+            for (symbol, instrument) in symbols {
+                registry.insert(exchange, symbol, instrument.clone());
+            }
+        }
+        registry
+    }
+
+    /// Fetches from the exchanges every subscribed symbol that is not in the registry yet.
+    pub async fn fetch_missing(&mut self, config: &AppConfig) -> anyhow::Result<()> {
+        for symbol in config.symbols() {
+            if self.get(binance::EXCHANGE_NAME, &symbol).is_none() {
+                let instrument = binance::fetch_instrument(&config.binance, &symbol)
+                    .await
+                    .with_context(|| format!("fetching binance instrument {symbol}"))?;
+                info!("fetched binance instrument {}: {:?}", symbol, instrument);
+                self.insert(binance::EXCHANGE_NAME, &symbol, instrument);
+            }
+            if self.get(bitstamp::EXCHANGE_NAME, &symbol).is_none() {
+                let instrument = bitstamp::fetch_instrument(&config.bitstamp, &symbol)
+                    .await
+                    .with_context(|| format!("fetching bitstamp instrument {symbol}"))?;
+                info!("fetched bitstamp instrument {}: {:?}", symbol, instrument);
+                self.insert(bitstamp::EXCHANGE_NAME, &symbol, instrument);
+            }
+        }
+        Ok(())
+    }
+
+    /// Symbols are resolved with the symbol rules of the exchange, so `ETH/BTC` and `ethbtc` are
+    /// the same Binance instrument. They are case insensitive, as the config crate lowercases the
+    /// table keys.
+    pub fn insert(&mut self, exchange: &str, symbol: &str, instrument: InstrumentConfig) {
+        let symbol = self.exchange_symbol(exchange, symbol);
+        self.instruments
+            .insert((exchange.to_owned(), symbol), instrument);
+    }
+
+    pub fn get(&self, exchange: &str, symbol: &str) -> Option<&InstrumentConfig> {
+        self.instruments
+            .get(&(exchange.to_owned(), self.exchange_symbol(exchange, symbol)))
+    }
+
+    /// Decimal places of the tick and lot sizes of `symbol`, the finest ones across exchanges.
+    pub fn scale(&self, symbol: &str) -> Option<ScaleConfig> {
+        self.instruments
+            .iter()
+            .filter(|((exchange, instrument_symbol), _)| {
+                *instrument_symbol == self.exchange_symbol(exchange, symbol)
+            })
+            .map(|(_, instrument)| ScaleConfig {
+                price: instrument.tick_size.normalize().scale(),
+                quantity: instrument.lot_size.normalize().scale(),
+            })
+            .reduce(|a, b| ScaleConfig {
+                price: a.price.max(b.price),
+                quantity: a.quantity.max(b.quantity),
+            })
+    }
+
+    /// Exchanges without symbol rules take the symbols as written.
+    fn exchange_symbol(&self, exchange: &str, symbol: &str) -> String {
+        match self.symbol_rules.get(exchange) {
+            Some(rules) => rules.exchange_symbol(symbol).to_lowercase(),
+            None => symbol.to_lowercase(),
+        }
+    }
+
+    pub fn to_proto(&self) -> orderbook::Instruments {
+        orderbook::Instruments {
+            instruments: self
+                .instruments
+                .iter()
+                .map(|((exchange, symbol), instrument)| orderbook::Instrument {
+                    exchange: exchange.clone(),
+                    symbol: symbol.clone(),
+                    base: instrument.base.clone(),
+                    quote: instrument.quote.clone(),
+                    tick_size: instrument.tick_size.normalize().to_string(),
+                    lot_size: instrument.lot_size.normalize().to_string(),
+                    min_notional: instrument.min_notional.normalize().to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl InstrumentConfig {
+    /// Whether a level respects the tick and lot sizes of the instrument.
+    pub fn is_valid_level(&self, price: Decimal, amount: Decimal) -> bool {
+        is_multiple(price, self.tick_size) && is_multiple(amount, self.lot_size)
+    }
+}
+
+fn is_multiple(value: Decimal, step: Decimal) -> bool {
+    step.is_zero() || (value % step).is_zero()
+}
+
+/// Step size of a value published with `decimals` decimal places.
+pub(crate) fn step_from_decimals(decimals: u32) -> Decimal {
+    Decimal::new(1, decimals)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_valid_levels() {
+        let instrument = InstrumentConfig {
+            base: "ETH".to_owned(),
+            quote: "BTC".to_owned(),
+            tick_size: "0.00001".parse().unwrap(),
+            lot_size: step_from_decimals(4),
+            min_notional: Decimal::ZERO,
+        };
+        let dec = |value: &str| value.parse::<Decimal>().unwrap();
+        assert!(instrument.is_valid_level(dec("0.06512000"), dec("1.2340")));
+        assert!(!instrument.is_valid_level(dec("0.065125"), dec("1.2340")));
+        assert!(!instrument.is_valid_level(dec("0.06512"), dec("1.23405")));
+    }
+
+    #[test]
+    fn test_scale() {
+        let instrument = |tick_size: &str, lot_size: &str| InstrumentConfig {
+            base: "ETH".to_owned(),
+            quote: "BTC".to_owned(),
+            tick_size: tick_size.parse().unwrap(),
+            lot_size: lot_size.parse().unwrap(),
+            min_notional: Decimal::ZERO,
+        };
+        let mut registry = InstrumentRegistry::new(HashMap::from([
+            ("binance".to_owned(), SymbolRules::concatenated()),
+            ("kraken".to_owned(), SymbolRules::kraken()),
+        ]));
+        registry.insert("binance", "ETHBTC", instrument("0.00001", "0.0001"));
+        registry.insert("bitstamp", "ethbtc", instrument("0.000001", "0.00100000"));
+        assert_eq!(
+            Some(ScaleConfig {
+                price: 6,
+                quantity: 4
+            }),
+            registry.scale("ethbtc")
+        );
+        registry.insert("kraken", "eth/xbt", instrument("0.0000001", "0.1"));
+        assert_eq!(
+            Some(ScaleConfig {
+                price: 7,
+                quantity: 4
+            }),
+            registry.scale("ETH/BTC")
+        );
+        assert!(registry.get("binance", "ETH/BTC").is_some());
+        assert!(registry.get("kraken", "ETH/BTC").is_some());
+        assert_eq!(None, registry.scale("ltcbtc"));
+    }
+}
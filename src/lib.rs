@@ -0,0 +1,32 @@
+pub mod aggregator;
+pub mod analytics;
+pub mod arbitrage;
+pub mod binance;
+pub mod bitstamp;
+mod common;
+pub mod configuration;
+mod decimal;
+pub mod deltas;
+pub mod file_sink;
+pub mod grouping;
+pub mod history;
+pub mod http_api;
+pub mod instruments;
+pub mod pipeline;
+pub mod routing;
+pub mod server;
+pub mod sink;
+pub mod symbols;
+pub mod synthetic;
+pub mod threshold;
+mod tls;
+pub mod trades;
+pub mod vwap;
+pub mod websocket;
+
+pub mod orderbook {
+    tonic::include_proto!("orderbook");
+}
+
+pub use orderbook::Summary;
+pub use pipeline::{Pipeline, PipelineBuilder};
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use exponential_backoff::Backoff;
+use futures::Future;
+use log::{error, info};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::aggregator::{AggregatorConfig, ExchangeOrders};
+use crate::configuration::{AppConfig, BackoffConfig, SinkConfig};
+use crate::history::History;
+use crate::instruments::InstrumentRegistry;
+use crate::orderbook::{self, Analytics, BestBidOffer, Opportunity, Summary};
+use crate::server::OrderBookAggregatorService;
+use crate::sink::{FileSink, GrpcSink, HttpSink, Sink, StdoutSink, UdpSink, WebSocketSink};
+use crate::trades::Trade;
+use crate::{aggregator, analytics, binance, bitstamp, history};
+
+const OPPORTUNITIES_CHANNEL_SIZE: usize = 64;
+const TRADES_CHANNEL_SIZE: usize = 256;
+
+/// Configures which parts of the aggregation pipeline get spawned.
+pub struct PipelineBuilder {
+    config: AppConfig,
+    instruments: InstrumentRegistry,
+    grpc_server: bool,
+    sinks: Vec<Arc<dyn Sink>>,
+}
+
+/// A running pipeline: exchange connectors feeding the aggregator, which feeds the sinks.
+pub struct Pipeline {
+    tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+    summaries: watch::Receiver<Summary>,
+    bbo: watch::Receiver<BestBidOffer>,
+    analytics: watch::Receiver<Analytics>,
+    opportunities: broadcast::Sender<Opportunity>,
+    trades: broadcast::Sender<orderbook::Trade>,
+    instruments: Arc<InstrumentRegistry>,
+    history: Arc<Mutex<History>>,
+    cancellation_token: CancellationToken,
+}
+
+impl PipelineBuilder {
+    pub fn new(config: AppConfig) -> Self {
+        Self {
+            instruments: InstrumentRegistry::from_config(&config),
+            config,
+            grpc_server: true,
+            sinks: vec![],
+        }
+    }
+
+    /// Fetches from the exchanges the instruments that are not configured, when
+    /// `fetch_instruments` is enabled.
+    pub async fn fetch_instruments(mut self) -> anyhow::Result<Self> {
+        if self.config.fetch_instruments {
+            self.instruments.fetch_missing(&self.config).await?;
+        }
+        Ok(self)
+    }
+
+    /// Whether to expose the aggregated book through the gRPC server. Enabled by default.
+    pub fn grpc_server(mut self, enabled: bool) -> Self {
+        self.grpc_server = enabled;
+        self
+    }
+
+    /// Adds a sink fed with the summaries, besides the configured ones.
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Spawns all the pipeline tasks on the current tokio runtime.
+    pub fn spawn(self) -> Pipeline {
+        let app_config = self.config;
+        let instruments = Arc::new(self.instruments);
+        let (sender, receiver) = mpsc::channel(app_config.channel_size);
+        let (summary_sender, summary_receiver) = watch::channel(Summary::default());
+        let (bbo_sender, bbo_receiver) = watch::channel(BestBidOffer::default());
+        let (analytics_sender, analytics_receiver) = watch::channel(Analytics::default());
+        let (opportunities, _) = broadcast::channel(OPPORTUNITIES_CHANNEL_SIZE);
+        let (trade_sender, trade_receiver) = mpsc::channel(app_config.channel_size);
+        let (trades, _) = broadcast::channel(TRADES_CHANNEL_SIZE);
+        let mut tasks = vec![];
+        let cancellation_token = CancellationToken::new();
+        spawn_connectors(
+            &mut tasks,
+            &cancellation_token,
+            &app_config,
+            &instruments,
+            app_config.symbol.clone(),
+            ("bitstamp_stream", "binance_stream"),
+            sender.clone(),
+        );
+        if app_config.trades.is_some() {
+            spawn_trade_connectors(&mut tasks, &cancellation_token, &app_config, trade_sender);
+        }
+        if let Some(synthetic) = &app_config.synthetic {
+            spawn_connectors(
+                &mut tasks,
+                &cancellation_token,
+                &app_config,
+                &instruments,
+                synthetic.base_leg.clone(),
+                ("bitstamp_base_leg_stream", "binance_base_leg_stream"),
+                sender.clone(),
+            );
+            spawn_connectors(
+                &mut tasks,
+                &cancellation_token,
+                &app_config,
+                &instruments,
+                synthetic.quote_leg.clone(),
+                ("bitstamp_quote_leg_stream", "binance_quote_leg_stream"),
+                sender,
+            );
+        }
+        let fees = HashMap::from([
+            (binance::EXCHANGE_NAME.to_owned(), app_config.binance.fees),
+            (bitstamp::EXCHANGE_NAME.to_owned(), app_config.bitstamp.fees),
+        ]);
+        let aggregator_config = AggregatorConfig {
+            symbol: app_config.symbol.clone(),
+            max_levels: app_config.max_aggregated_levels,
+            consolidate: app_config.consolidate_levels,
+            fee_adjusted: app_config.fee_adjusted,
+            fees: fees.clone(),
+            synthetic: app_config.synthetic.clone(),
+            scale: app_config.scale_for(&app_config.symbol, &instruments),
+            trade_volume_window: app_config
+                .trades
+                .as_ref()
+                .map_or(Duration::ZERO, |trades| trades.volume_window),
+        };
+        let opportunity_sender = opportunities.clone();
+        let trades_sender = trades.clone();
+        spawn_task(
+            &mut tasks,
+            "orders_aggregator",
+            cancellation_token.clone(),
+            move || {
+                aggregator::orders_aggregator(
+                    receiver,
+                    trade_receiver,
+                    summary_sender,
+                    bbo_sender,
+                    opportunity_sender,
+                    trades_sender,
+                    aggregator_config,
+                )
+            },
+        );
+        let summaries = summary_receiver.clone();
+        let analytics_config = app_config.analytics.clone();
+        spawn_task(
+            &mut tasks,
+            "book_analytics",
+            cancellation_token.clone(),
+            move || analytics::analytics_publisher(summaries, analytics_sender, analytics_config),
+        );
+        let history = Arc::new(Mutex::new(History::new(&app_config.history)));
+        let summaries = summary_receiver.clone();
+        let recorded_history = history.clone();
+        spawn_task(
+            &mut tasks,
+            "history_recorder",
+            cancellation_token.clone(),
+            move || history::history_recorder(summaries, recorded_history),
+        );
+        let service = OrderBookAggregatorService {
+            ticks: summary_receiver.clone(),
+            bbo: bbo_receiver.clone(),
+            analytics: analytics_receiver.clone(),
+            opportunities: opportunities.clone(),
+            trades: trades.clone(),
+            instruments: instruments.clone(),
+            history: history.clone(),
+            fees: Arc::new(fees),
+            fee_adjusted: app_config.fee_adjusted,
+        };
+        let mut sinks = self.sinks;
+        for sink_config in app_config.sinks() {
+            let sink: Arc<dyn Sink> = match sink_config {
+                SinkConfig::Grpc if !self.grpc_server => continue,
+                SinkConfig::Grpc => Arc::new(GrpcSink::new(
+                    service.clone(),
+                    app_config.server.clone(),
+                    cancellation_token.clone(),
+                )),
+                SinkConfig::File(config) => {
+                    Arc::new(FileSink::new(config, app_config.max_aggregated_levels))
+                }
+                SinkConfig::Stdout => Arc::new(StdoutSink),
+                SinkConfig::Udp(config) => Arc::new(UdpSink::new(config)),
+                SinkConfig::Http(config) => {
+                    Arc::new(HttpSink::new(config, app_config.symbol_aliases()))
+                }
+                SinkConfig::WebSocket(config) => {
+                    Arc::new(WebSocketSink::new(config, app_config.symbol_aliases()))
+                }
+            };
+            sinks.push(sink);
+        }
+        for sink in sinks {
+            spawn_sink(
+                &mut tasks,
+                sink,
+                summary_receiver.clone(),
+                cancellation_token.clone(),
+                &app_config.backoff,
+            );
+        }
+        Pipeline {
+            tasks,
+            summaries: summary_receiver,
+            bbo: bbo_receiver,
+            analytics: analytics_receiver,
+            opportunities,
+            trades,
+            instruments,
+            history,
+            cancellation_token,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn builder(config: AppConfig) -> PipelineBuilder {
+        PipelineBuilder::new(config)
+    }
+
+    /// Returns a receiver that observes every summary published by the aggregator.
+    pub fn summaries(&self) -> watch::Receiver<Summary> {
+        self.summaries.clone()
+    }
+
+    /// Returns a receiver that observes the best bid and offer, changed only when they move.
+    pub fn bbo(&self) -> watch::Receiver<BestBidOffer> {
+        self.bbo.clone()
+    }
+
+    /// Returns a receiver that observes the analytics of every summary.
+    pub fn analytics(&self) -> watch::Receiver<Analytics> {
+        self.analytics.clone()
+    }
+
+    /// Subscribes to the cross-exchange arbitrage opportunities found by the aggregator.
+    pub fn opportunities(&self) -> broadcast::Receiver<Opportunity> {
+        self.opportunities.subscribe()
+    }
+
+    /// Subscribes to the trades of every exchange. Only published when `[trades]` is configured.
+    pub fn trades(&self) -> broadcast::Receiver<orderbook::Trade> {
+        self.trades.subscribe()
+    }
+
+    /// Returns the instrument metadata the connectors validate levels against.
+    pub fn instruments(&self) -> Arc<InstrumentRegistry> {
+        self.instruments.clone()
+    }
+
+    /// Returns the bars recorded from the aggregated books.
+    pub fn history(&self) -> Arc<Mutex<History>> {
+        self.history.clone()
+    }
+
+    /// Signals every pipeline task to stop. Use [`Pipeline::join`] to wait for them.
+    pub fn shutdown(&self) {
+        self.cancellation_token.cancel();
+    }
+
+    /// Waits for all the pipeline tasks to finish, logging their errors.
+    pub async fn join(self) {
+        for task in self.tasks {
+            match task.await {
+                Ok(task_result) => {
+                    if let Err(e) = task_result {
+                        error!("Task finished with error: {}", e)
+                    }
+                }
+                Err(e) => error!("Error joining task: {}", e),
+            }
+        }
+    }
+}
+
+fn spawn_connectors(
+    tasks: &mut Vec<JoinHandle<anyhow::Result<()>>>,
+    cancellation_token: &CancellationToken,
+    app_config: &AppConfig,
+    instruments: &InstrumentRegistry,
+    symbol: String,
+    (bitstamp_task_name, binance_task_name): (&'static str, &'static str),
+    sender: mpsc::Sender<ExchangeOrders>,
+) {
+    let bitstamp_symbol = symbol.clone();
+    let bitstamp_config = app_config.bitstamp.clone();
+    let bitstamp_instrument = instruments.get(bitstamp::EXCHANGE_NAME, &symbol).cloned();
+    let bitstamp_sender = sender.clone();
+    spawn_task_backoff(
+        tasks,
+        bitstamp_task_name,
+        cancellation_token.clone(),
+        &app_config.backoff,
+        move || {
+            bitstamp::bitstamp_stream(
+                bitstamp_config.clone(),
+                bitstamp_symbol.clone(),
+                bitstamp_instrument.clone(),
+                bitstamp_sender.clone(),
+            )
+        },
+    );
+    let binance_config = app_config.binance.clone();
+    let binance_instrument = instruments.get(binance::EXCHANGE_NAME, &symbol).cloned();
+    spawn_task_backoff(
+        tasks,
+        binance_task_name,
+        cancellation_token.clone(),
+        &app_config.backoff,
+        move || {
+            binance::binance_stream(
+                binance_config.clone(),
+                symbol.clone(),
+                binance_instrument.clone(),
+                sender.clone(),
+            )
+        },
+    );
+}
+
+fn spawn_trade_connectors(
+    tasks: &mut Vec<JoinHandle<anyhow::Result<()>>>,
+    cancellation_token: &CancellationToken,
+    app_config: &AppConfig,
+    sender: mpsc::Sender<Trade>,
+) {
+    let bitstamp_symbol = app_config.symbol.clone();
+    let bitstamp_config = app_config.bitstamp.clone();
+    let bitstamp_sender = sender.clone();
+    spawn_task_backoff(
+        tasks,
+        "bitstamp_trades_stream",
+        cancellation_token.clone(),
+        &app_config.backoff,
+        move || {
+            bitstamp::bitstamp_trades_stream(
+                bitstamp_config.clone(),
+                bitstamp_symbol.clone(),
+                bitstamp_sender.clone(),
+            )
+        },
+    );
+    let binance_symbol = app_config.symbol.clone();
+    let binance_config = app_config.binance.clone();
+    spawn_task_backoff(
+        tasks,
+        "binance_trades_stream",
+        cancellation_token.clone(),
+        &app_config.backoff,
+        move || {
+            binance::binance_trades_stream(
+                binance_config.clone(),
+                binance_symbol.clone(),
+                sender.clone(),
+            )
+        },
+    );
+}
+
+fn spawn_task_backoff<F, T: Future<Output = Result<(), anyhow::Error>> + Send + 'static>(
+    tasks: &mut Vec<JoinHandle<T::Output>>,
+    task_name: &'static str,
+    cancellation_token: CancellationToken,
+    backoff_config: &BackoffConfig,
+    f: F,
+) where
+    F: Fn() -> T + Send + 'static,
+{
+    let backoff = Backoff::new(
+        backoff_config.retries,
+        backoff_config.min,
+        Some(backoff_config.max),
+    );
+    let async_block = async move {
+        for duration in &backoff {
+            tokio::select! {
+                result = f() => {
+                    match result {
+                        Ok(()) => {
+                            info!("task {} ended gracefully. Shutting down other tasks.", task_name);
+                        },
+                        Err(ref e) => {
+                            error!("task {} ended with error {}. Backing off.", task_name, e);
+                            time::sleep(duration).await;
+                        }
+                    }
+                }
+                _ = cancellation_token.cancelled() => {
+                    info!("task {} cancelled. Exiting", task_name);
+                    bail!("task {} cancelled", task_name)
+                }
+            }
+        }
+        cancellation_token.cancel();
+        bail!("backoff retries exhausted for task {}", task_name);
+    };
+    let task_handle = tokio::spawn(async_block);
+    tasks.push(task_handle);
+}
+
+/// Runs a sink on its own supervisor, restarting it with backoff when it fails. A run lasting longer
+/// than the longest backoff delay resets the backoff. A sink that ends, or runs out of retries, only
+/// stops itself: the rest of the pipeline keeps running.
+fn spawn_sink(
+    tasks: &mut Vec<JoinHandle<anyhow::Result<()>>>,
+    sink: Arc<dyn Sink>,
+    summaries: watch::Receiver<Summary>,
+    cancellation_token: CancellationToken,
+    backoff_config: &BackoffConfig,
+) {
+    let backoff = Backoff::new(
+        backoff_config.retries,
+        backoff_config.min,
+        Some(backoff_config.max),
+    );
+    let healthy_run = backoff_config.max;
+    let task_name = sink.name();
+    let async_block = async move {
+        let mut delays = backoff.iter();
+        loop {
+            let started = Instant::now();
+            let error = tokio::select! {
+                result = sink.run(summaries.clone()) => match result {
+                    Ok(()) => {
+                        info!("sink {} ended gracefully", task_name);
+                        return Ok(());
+                    }
+                    Err(e) => e,
+                },
+                _ = cancellation_token.cancelled() => {
+                    info!("sink {} cancelled. Exiting", task_name);
+                    return Ok(());
+                }
+            };
+            if started.elapsed() >= healthy_run {
+                delays = backoff.iter();
+            }
+            let Some(delay) = delays.next() else {
+                error!(
+                    "sink {} ended with error {}. Retries exhausted, stopping it.",
+                    task_name, error
+                );
+                bail!("backoff retries exhausted for sink {}", task_name);
+            };
+            error!(
+                "sink {} ended with error {}. Backing off.",
+                task_name, error
+            );
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = cancellation_token.cancelled() => {
+                    info!("sink {} cancelled. Exiting", task_name);
+                    return Ok(());
+                }
+            }
+        }
+    };
+    tasks.push(tokio::spawn(async_block));
+}
+
+fn spawn_task<F, T: Future<Output = Result<(), anyhow::Error>> + Send + 'static>(
+    tasks: &mut Vec<JoinHandle<T::Output>>,
+    task_name: &'static str,
+    cancellation_token: CancellationToken,
+    f: F,
+) where
+    F: FnOnce() -> T + Send + 'static,
+{
+    let async_block = async move {
+        tokio::select! {
+            result = f() => {
+                match result {
+                    Ok(()) => {
+                        info!("task {} ended gracefully. Shutting down other tasks.", task_name);
+                    },
+                    Err(ref e) => {
+                        error!("task {} ended with error {}. Shutting down other tasks.", task_name, e)
+                    }
+                }
+                cancellation_token.cancel();
+                result
+            }
+            _ = cancellation_token.cancelled() => {
+                info!("task {} cancelled. Exiting", task_name);
+                bail!("task {} cancelled", task_name)
+            }
+        }
+    };
+    let task_handle = tokio::spawn(async_block);
+    tasks.push(task_handle);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+
+    /// Fails every run, after `run_time` when set.
+    struct FailingSink {
+        runs: Arc<AtomicU32>,
+        run_time: Duration,
+    }
+
+    #[async_trait]
+    impl Sink for FailingSink {
+        fn name(&self) -> &'static str {
+            "failing_sink"
+        }
+
+        async fn run(&self, _summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            time::sleep(self.run_time).await;
+            bail!("sink failed")
+        }
+    }
+
+    fn backoff_config() -> BackoffConfig {
+        BackoffConfig {
+            retries: 2,
+            min: Duration::from_millis(1),
+            max: Duration::from_millis(10),
+        }
+    }
+
+    async fn run_sink(run_time: Duration, until: Duration) -> (u32, bool, CancellationToken) {
+        let runs = Arc::new(AtomicU32::new(0));
+        let sink = FailingSink {
+            runs: runs.clone(),
+            run_time,
+        };
+        let cancellation_token = CancellationToken::new();
+        let mut tasks = vec![];
+        spawn_sink(
+            &mut tasks,
+            Arc::new(sink),
+            watch::channel(Summary::default()).1,
+            cancellation_token.clone(),
+            &backoff_config(),
+        );
+        let task = tasks.pop().unwrap();
+        let finished = time::timeout(until, task).await.is_ok();
+        (runs.load(Ordering::SeqCst), finished, cancellation_token)
+    }
+
+    #[tokio::test]
+    async fn test_sink_gives_up_alone() {
+        let (runs, finished, cancellation_token) =
+            run_sink(Duration::ZERO, Duration::from_secs(1)).await;
+        assert!(finished);
+        // the first run and one per retry
+        assert_eq!(4, runs);
+        assert!(!cancellation_token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_sink_backoff_reset_after_healthy_run() {
+        let (runs, finished, cancellation_token) =
+            run_sink(Duration::from_millis(20), Duration::from_millis(200)).await;
+        assert!(!finished);
+        assert!(runs > 4);
+        cancellation_token.cancel();
+    }
+}
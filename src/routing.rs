@@ -0,0 +1,431 @@
+use std::collections::{HashMap, HashSet};
+
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::configuration::FeeConfig;
+use crate::decimal::{format_scaled, parse_or, to_f64};
+use crate::orderbook::{ChildOrder, RouteRequest, RouteSuggestion, Side, Summary, VenueConstraint};
+use crate::vwap::levels_for_side;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RoutingError {
+    #[error("unknown side {0}")]
+    UnknownSide(i32),
+    #[error("route quantity must be a positive decimal, got {0}")]
+    InvalidQuantity(f64),
+    #[error("invalid {field} {value} for {exchange}")]
+    InvalidConstraint {
+        exchange: String,
+        field: &'static str,
+        value: f64,
+    },
+}
+
+/// A venue constraint of the request with its values as exact decimals.
+struct Constraint {
+    min_size: Decimal,
+    max_allocation: Option<Decimal>,
+    taker_fee: Option<Decimal>,
+    available_balance: Option<Decimal>,
+}
+
+impl TryFrom<&VenueConstraint> for Constraint {
+    type Error = RoutingError;
+
+    fn try_from(venue: &VenueConstraint) -> Result<Self, Self::Error> {
+        let invalid = |field, value| RoutingError::InvalidConstraint {
+            exchange: venue.exchange.clone(),
+            field,
+            value,
+        };
+        let decimal = |field, value: f64| match Decimal::try_from(value) {
+            Ok(decimal) if value.is_finite() => Ok(decimal),
+            _ => Err(invalid(field, value)),
+        };
+        let optional = |field, value: Option<f64>| value.map(|v| decimal(field, v)).transpose();
+        // A fee can't take more than the whole notional.
+        let taker_fee = match venue.taker_bps {
+            Some(bps) if bps.abs() > 10_000.0 => return Err(invalid("taker_bps", bps)),
+            bps => optional("taker_bps", bps)?.map(|bps| bps / Decimal::from(10_000)),
+        };
+        Ok(Constraint {
+            min_size: decimal("min_size", venue.min_size)?,
+            max_allocation: optional("max_allocation", venue.max_allocation)?,
+            taker_fee,
+            available_balance: optional("available_balance", venue.available_balance)?,
+        })
+    }
+}
+
+/// A level of the aggregated book with its price after the exchange taker fee.
+struct RouteLevel<'a> {
+    exchange: &'a str,
+    price: Decimal,
+    amount: Decimal,
+    effective_price: Decimal,
+}
+
+#[derive(Debug, Default)]
+struct Allocation {
+    quantity: Decimal,
+    notional: Decimal,
+    fee: Decimal,
+    limit_price: Decimal,
+}
+
+/// Splits the requested quantity across exchanges taking the cheapest levels of the aggregated
+/// book after fees, within each exchange allocation and balance limits. Exchanges whose allocation
+/// ends up below their minimum size are left out and the remainder is routed again to the others.
+/// The configured `fees` apply unless the request overrides them, and none are added when the
+/// summary is `fee_adjusted`.
+pub fn suggest_route(
+    summary: &Summary,
+    request: &RouteRequest,
+    fees: &HashMap<String, FeeConfig>,
+    fee_adjusted: bool,
+) -> Result<RouteSuggestion, RoutingError> {
+    let side = Side::from_i32(request.side).ok_or(RoutingError::UnknownSide(request.side))?;
+    let quantity = match Decimal::try_from(request.quantity) {
+        Ok(quantity) if request.quantity.is_finite() && quantity > Decimal::ZERO => quantity,
+        _ => return Err(RoutingError::InvalidQuantity(request.quantity)),
+    };
+    let constraints = request
+        .venues
+        .iter()
+        .map(|venue| Ok((venue.exchange.as_str(), Constraint::try_from(venue)?)))
+        .collect::<Result<HashMap<_, _>, RoutingError>>()?;
+    let fee_for = |exchange: &str| {
+        if fee_adjusted {
+            return Decimal::ZERO;
+        }
+        match constraints.get(exchange).and_then(|c| c.taker_fee) {
+            Some(taker_fee) => taker_fee,
+            None => fees
+                .get(exchange)
+                .map_or(Decimal::ZERO, FeeConfig::taker_fee_decimal),
+        }
+    };
+    let mut levels: Vec<RouteLevel> = levels_for_side(summary, side)
+        .iter()
+        .map(|level| {
+            let price = parse_or(&level.price_decimal, level.price);
+            let fee = fee_for(&level.exchange);
+            let effective_price = match side {
+                Side::Buy => price * (Decimal::ONE + fee),
+                Side::Sell => price * (Decimal::ONE - fee),
+            };
+            RouteLevel {
+                exchange: &level.exchange,
+                price,
+                amount: parse_or(&level.amount_decimal, level.amount),
+                effective_price,
+            }
+        })
+        .collect();
+    levels.sort_by(|a, b| match side {
+        Side::Buy => a.effective_price.cmp(&b.effective_price),
+        Side::Sell => b.effective_price.cmp(&a.effective_price),
+    });
+    let mut excluded = HashSet::new();
+    loop {
+        let allocations = allocate(&levels, side, quantity, &constraints, &excluded);
+        let below_minimum: Vec<&str> = allocations
+            .iter()
+            .filter(|(exchange, allocation)| {
+                allocation.quantity
+                    < constraints
+                        .get(*exchange)
+                        .map_or(Decimal::ZERO, |c| c.min_size)
+            })
+            .map(|(exchange, _)| *exchange)
+            .collect();
+        if below_minimum.is_empty() {
+            return Ok(route_suggestion(allocations, side, quantity, summary));
+        }
+        excluded.extend(below_minimum);
+    }
+}
+
+fn allocate<'a>(
+    levels: &[RouteLevel<'a>],
+    side: Side,
+    quantity: Decimal,
+    constraints: &HashMap<&str, Constraint>,
+    excluded: &HashSet<&str>,
+) -> Vec<(&'a str, Allocation)> {
+    let mut allocations: Vec<(&str, Allocation)> = vec![];
+    let mut remaining = quantity;
+    for level in levels {
+        if remaining <= Decimal::ZERO {
+            break;
+        }
+        let exchange = level.exchange;
+        if excluded.contains(exchange) {
+            continue;
+        }
+        let index = match allocations.iter().position(|(e, _)| *e == exchange) {
+            Some(index) => index,
+            None => {
+                allocations.push((exchange, Allocation::default()));
+                allocations.len() - 1
+            }
+        };
+        let allocation = &mut allocations[index].1;
+        let mut available = level.amount.min(remaining);
+        if let Some(constraint) = constraints.get(exchange) {
+            if let Some(max_allocation) = constraint.max_allocation {
+                available = available.min(max_allocation - allocation.quantity);
+            }
+            if let Some(balance) = constraint.available_balance {
+                let balance_quantity = match side {
+                    Side::Buy => (balance - allocation.notional - allocation.fee)
+                        .checked_div(level.effective_price)
+                        .unwrap_or_default(),
+                    Side::Sell => balance - allocation.quantity,
+                };
+                available = available.min(balance_quantity);
+            }
+        }
+        if available <= Decimal::ZERO {
+            continue;
+        }
+        allocation.quantity += available;
+        allocation.notional += available * level.price;
+        allocation.fee += (level.effective_price - level.price).abs() * available;
+        allocation.limit_price = level.price;
+        remaining -= available;
+    }
+    allocations.retain(|(_, allocation)| allocation.quantity > Decimal::ZERO);
+    allocations
+}
+
+/// Exact values are published with the summary scales, prices and quantities rounded to theirs
+/// and fees to both added up.
+fn route_suggestion(
+    allocations: Vec<(&str, Allocation)>,
+    side: Side,
+    quantity: Decimal,
+    summary: &Summary,
+) -> RouteSuggestion {
+    let price_scale = summary.price_scale;
+    let quantity_scale = summary.quantity_scale;
+    let routed: Decimal = allocations.iter().map(|(_, a)| a.quantity).sum();
+    let notional: Decimal = allocations.iter().map(|(_, a)| a.notional).sum();
+    let fees: Decimal = allocations.iter().map(|(_, a)| a.fee).sum();
+    let average_price = if routed > Decimal::ZERO {
+        match side {
+            Side::Buy => (notional + fees) / routed,
+            Side::Sell => (notional - fees) / routed,
+        }
+    } else {
+        Decimal::ZERO
+    };
+    let unfilled_quantity = (quantity - routed).max(Decimal::ZERO);
+    RouteSuggestion {
+        orders: allocations
+            .into_iter()
+            .map(|(exchange, allocation)| {
+                let average_price = allocation.notional / allocation.quantity;
+                ChildOrder {
+                    exchange: exchange.to_owned(),
+                    quantity: to_f64(allocation.quantity),
+                    limit_price: to_f64(allocation.limit_price),
+                    average_price: to_f64(average_price),
+                    fee: to_f64(allocation.fee),
+                    quantity_decimal: format_scaled(allocation.quantity, quantity_scale),
+                    limit_price_decimal: format_scaled(allocation.limit_price, price_scale),
+                    average_price_decimal: format_scaled(average_price, price_scale),
+                    fee_decimal: format_scaled(allocation.fee, price_scale + quantity_scale),
+                }
+            })
+            .collect(),
+        quantity: to_f64(routed),
+        average_price: to_f64(average_price),
+        unfilled_quantity: to_f64(unfilled_quantity),
+        quantity_decimal: format_scaled(routed, quantity_scale),
+        average_price_decimal: format_scaled(average_price, price_scale),
+        unfilled_quantity_decimal: format_scaled(unfilled_quantity, quantity_scale),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::orderbook;
+
+    use super::*;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> orderbook::Level {
+        orderbook::Level {
+            exchange: exchange.to_owned(),
+            price,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            asks: vec![
+                level("a", 100.0, 1.0),
+                level("b", 100.05, 1.0),
+                level("a", 101.0, 2.0),
+                level("b", 102.0, 2.0),
+            ],
+            price_scale: 2,
+            quantity_scale: 1,
+            ..Default::default()
+        }
+    }
+
+    fn constraint(exchange: &str) -> VenueConstraint {
+        VenueConstraint {
+            exchange: exchange.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn quantities(suggestion: &RouteSuggestion) -> Vec<(&str, f64)> {
+        suggestion
+            .orders
+            .iter()
+            .map(|o| (o.exchange.as_str(), o.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_fees_change_routing_order() {
+        let request = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 1.0,
+            venues: vec![VenueConstraint {
+                taker_bps: Some(10.0),
+                ..constraint("a")
+            }],
+        };
+        let suggestion = suggest_route(&summary(), &request, &HashMap::new(), false).unwrap();
+        assert_eq!(vec![("b", 1.0)], quantities(&suggestion));
+        assert_eq!(100.05, suggestion.average_price);
+        assert_eq!("100.05", suggestion.average_price_decimal);
+        assert_eq!("1.0", suggestion.quantity_decimal);
+        assert_eq!(0.0, suggestion.unfilled_quantity);
+    }
+
+    #[test]
+    fn test_configured_fees() {
+        let request = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 1.0,
+            venues: vec![],
+        };
+        let fees = HashMap::from([(
+            "a".to_owned(),
+            FeeConfig {
+                taker_bps: 10.0,
+                maker_bps: 0.0,
+            },
+        )]);
+        let suggestion = suggest_route(&summary(), &request, &fees, false).unwrap();
+        assert_eq!(vec![("b", 1.0)], quantities(&suggestion));
+        // the summary prices already include the fees
+        let suggestion = suggest_route(&summary(), &request, &fees, true).unwrap();
+        assert_eq!(vec![("a", 1.0)], quantities(&suggestion));
+        assert_eq!(0.0, suggestion.orders[0].fee);
+    }
+
+    #[test]
+    fn test_max_allocation_and_balance() {
+        let request = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 4.0,
+            venues: vec![
+                VenueConstraint {
+                    max_allocation: Some(2.0),
+                    ..constraint("a")
+                },
+                VenueConstraint {
+                    available_balance: Some(151.05),
+                    ..constraint("b")
+                },
+            ],
+        };
+        let suggestion = suggest_route(&summary(), &request, &HashMap::new(), false).unwrap();
+        assert_eq!(vec![("a", 2.0), ("b", 1.5)], quantities(&suggestion));
+        assert_eq!(101.0, suggestion.orders[0].limit_price);
+        assert_eq!(0.5, suggestion.unfilled_quantity);
+    }
+
+    #[test]
+    fn test_min_size_excludes_venue() {
+        let request = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 3.5,
+            venues: vec![VenueConstraint {
+                min_size: 1.0,
+                ..constraint("b")
+            }],
+        };
+        // b would only get 0.5 after the first three levels
+        let suggestion = suggest_route(
+            &Summary {
+                asks: vec![
+                    level("a", 100.0, 3.0),
+                    level("b", 100.5, 0.5),
+                    level("a", 101.0, 2.0),
+                ],
+                ..Default::default()
+            },
+            &request,
+            &HashMap::new(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(vec![("a", 3.5)], quantities(&suggestion));
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let request = |quantity, venue| RouteRequest {
+            side: Side::Buy as i32,
+            quantity,
+            venues: vec![venue],
+        };
+        let route = |request| suggest_route(&summary(), &request, &HashMap::new(), false);
+        assert_eq!(
+            Err("route quantity must be a positive decimal, got inf".to_owned()),
+            route(request(f64::INFINITY, constraint("a"))).map_err(|e| e.to_string())
+        );
+        assert!(route(request(1e30, constraint("a"))).is_err());
+        assert_eq!(
+            Err(RoutingError::InvalidConstraint {
+                exchange: "a".to_owned(),
+                field: "available_balance",
+                value: f64::INFINITY,
+            }),
+            route(request(
+                1.0,
+                VenueConstraint {
+                    available_balance: Some(f64::INFINITY),
+                    ..constraint("a")
+                }
+            ))
+        );
+        for venue in [
+            VenueConstraint {
+                max_allocation: Some(1e30),
+                ..constraint("a")
+            },
+            VenueConstraint {
+                min_size: f64::NEG_INFINITY,
+                ..constraint("a")
+            },
+            VenueConstraint {
+                taker_bps: Some(1e20),
+                ..constraint("a")
+            },
+        ] {
+            assert!(route(request(1.0, venue)).is_err());
+        }
+    }
+}
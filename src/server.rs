@@ -1,60 +1,506 @@
-use std::net::ToSocketAddrs;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::{configuration, orderbook};
+use crate::configuration::{self, BindAddress, FeeConfig};
+use crate::deltas::DeltaEncoder;
+use crate::grouping::{group_summary, BucketSize, GroupingError};
+use crate::history::History;
+use crate::instruments::InstrumentRegistry;
+use crate::orderbook;
+use crate::routing::suggest_route;
+use crate::threshold::{ChangeThreshold, ThresholdFilter};
+use crate::tls::tls_incoming;
+use crate::vwap::{estimate_fill, FillTarget};
+use anyhow::{bail, Context};
 use async_trait::async_trait;
-use futures::{stream, Stream, StreamExt};
+use futures::{future, stream, Future, Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::watch::Receiver;
+use tokio::time::{self, Instant};
 use tokio_util::sync::CancellationToken;
+use tonic::codegen::http::{HeaderName, HeaderValue, Method};
 use tonic::transport::Server;
 use tonic::Status;
+use tonic_web::GrpcWebLayer;
+use tower::util::option_layer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
+const GRPC_WEB_REQUEST_HEADERS: [&str; 4] =
+    ["x-grpc-web", "content-type", "x-user-agent", "grpc-timeout"];
+const GRPC_WEB_RESPONSE_HEADERS: [&str; 3] =
+    ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+const CORS_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Same delay as hyper's `AddrIncoming`.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Serves the gRPC service on every bind address until `stop_signal` is cancelled. TCP addresses
+/// are served over TLS when configured.
 pub async fn grpc_server(
-    ticks: Receiver<orderbook::Summary>,
+    server: OrderBookAggregatorService,
     stop_signal: CancellationToken,
     server_config: configuration::Server,
 ) -> anyhow::Result<()> {
-    let server = OrderBookAggregatorService { ticks };
-    let port = server_config.port;
-    Server::builder()
-        .add_service(orderbook::orderbook_aggregator_server::OrderbookAggregatorServer::new(server))
-        .serve_with_shutdown(
-            format!("[::1]:{port}")
-                .to_socket_addrs()
-                .unwrap()
-                .next()
-                .unwrap(),
-            stop_signal.cancelled(),
-        )
-        .await?;
+    let grpc_web = server_config.grpc_web;
+    let cors = grpc_web
+        .then(|| cors_layer(&server_config.allowed_origins))
+        .transpose()?;
+    let router = || {
+        Server::builder()
+            .accept_http1(grpc_web)
+            .layer(option_layer(cors.clone()))
+            .layer(option_layer(grpc_web.then(GrpcWebLayer::new)))
+            .add_service(
+                orderbook::orderbook_aggregator_server::OrderbookAggregatorServer::new(
+                    server.clone(),
+                ),
+            )
+    };
+    let mut listeners: Vec<ServeFuture<'_>> = vec![];
+    for address in server_config.bind_addresses() {
+        let shutdown = stop_signal.cancelled();
+        let listener: ServeFuture<'_> = match &address {
+            BindAddress::Tcp(tcp_address) => {
+                let listener = TcpListener::bind(tcp_address)
+                    .await
+                    .with_context(|| format!("binding {address}"))?;
+                match &server_config.tls {
+                    Some(tls_config) => Box::pin(router().serve_with_incoming_shutdown(
+                        tls_incoming(listener, tls_config.clone(), server_config.grpc_web)?,
+                        shutdown,
+                    )),
+                    None => Box::pin(
+                        router().serve_with_incoming_shutdown(tcp_incoming(listener), shutdown),
+                    ),
+                }
+            }
+            BindAddress::Unix(path) => {
+                let listener = bind_unix(path).with_context(|| format!("binding {address}"))?;
+                Box::pin(router().serve_with_incoming_shutdown(unix_incoming(listener), shutdown))
+            }
+        };
+        info!("gRPC server listening on {}", address);
+        listeners.push(listener);
+    }
+    future::try_join_all(listeners).await?;
 
     Ok(())
 }
 
-struct OrderBookAggregatorService {
+type ServeFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>> + Send + 'a>>;
+
+fn tcp_incoming(listener: TcpListener) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => accept_error(e).await,
+            }
+        }
+    })
+}
+
+fn unix_incoming(listener: UnixListener) -> impl Stream<Item = io::Result<UnixStream>> {
+    stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => accept_error(e).await,
+            }
+        }
+    })
+}
+
+/// Logs an accept error, like running out of file descriptors, and waits before accepting again.
+/// Passing it to tonic instead would retry in a busy loop.
+pub(crate) async fn accept_error(error: io::Error) {
+    warn!("Error accepting connection: {}", error);
+    time::sleep(ACCEPT_ERROR_DELAY).await;
+}
+
+/// Replaces the socket left by a previous run, which would make the bind fail.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// CORS for gRPC-Web browser clients of `allowed_origins`, any origin when it contains `*`.
+/// No origin at all is a configuration error, as every browser call would be rejected.
+fn cors_layer(allowed_origins: &[String]) -> anyhow::Result<CorsLayer> {
+    if allowed_origins.is_empty() {
+        bail!("grpc_web is enabled without allowed_origins, browsers could not call the server");
+    }
+    let allow_origin = if allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).with_context(|| format!("invalid origin {origin}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::POST])
+        .allow_headers(GRPC_WEB_REQUEST_HEADERS.map(HeaderName::from_static))
+        .expose_headers(GRPC_WEB_RESPONSE_HEADERS.map(HeaderName::from_static))
+        .max_age(CORS_MAX_AGE))
+}
+
+/// Channels the gRPC service streams from.
+#[derive(Clone)]
+pub struct OrderBookAggregatorService {
     pub ticks: Receiver<orderbook::Summary>,
+    pub bbo: Receiver<orderbook::BestBidOffer>,
+    pub analytics: Receiver<orderbook::Analytics>,
+    pub opportunities: broadcast::Sender<orderbook::Opportunity>,
+    pub trades: broadcast::Sender<orderbook::Trade>,
+    pub instruments: Arc<InstrumentRegistry>,
+    pub history: Arc<Mutex<History>>,
+    /// Taker fees by exchange name used to route orders.
+    pub fees: Arc<HashMap<String, FeeConfig>>,
+    /// Whether the summary prices already include the fees.
+    pub fee_adjusted: bool,
 }
 
 type BookSummaryResponseStream =
     Pin<Box<dyn Stream<Item = Result<orderbook::Summary, Status>> + Send>>;
+type OpportunitiesResponseStream =
+    Pin<Box<dyn Stream<Item = Result<orderbook::Opportunity, Status>> + Send>>;
+type TradesResponseStream = Pin<Box<dyn Stream<Item = Result<orderbook::Trade, Status>> + Send>>;
+type BookDeltasResponseStream =
+    Pin<Box<dyn Stream<Item = Result<orderbook::BookDelta, Status>> + Send>>;
+type BookAnalyticsResponseStream =
+    Pin<Box<dyn Stream<Item = Result<orderbook::Analytics, Status>> + Send>>;
+type BboResponseStream =
+    Pin<Box<dyn Stream<Item = Result<orderbook::BestBidOffer, Status>> + Send>>;
 
 #[async_trait]
 impl orderbook::orderbook_aggregator_server::OrderbookAggregator for OrderBookAggregatorService {
     type BookSummaryStream = BookSummaryResponseStream;
+    type OpportunitiesStream = OpportunitiesResponseStream;
+    type TradesStream = TradesResponseStream;
+    type BboStream = BboResponseStream;
+    type BookDeltasStream = BookDeltasResponseStream;
+    type BookAnalyticsStream = BookAnalyticsResponseStream;
 
     async fn book_summary(
         &self,
-        _request: tonic::Request<orderbook::Empty>,
+        request: tonic::Request<orderbook::BookSummaryRequest>,
     ) -> Result<tonic::Response<Self::BookSummaryStream>, tonic::Status> {
-        let result_stream = stream::unfold(self.ticks.clone(), |mut ticks| async move {
-            if ticks.changed().await.is_ok() {
-                let cloned_summary = ticks.borrow().clone();
-                Some((cloned_summary, ticks))
-            } else {
-                None
-            }
-        })
-        .map(Result::<_, tonic::Status>::Ok);
+        let request = request.into_inner();
+        let min_interval =
+            conflation_interval(&request).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let bucket_size = request
+            .grouping
+            .map(BucketSize::try_from)
+            .transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut threshold_filter = request
+            .threshold
+            .map(ChangeThreshold::try_from)
+            .transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?
+            .map(ThresholdFilter::new);
+        let result_stream = watch_stream(self.ticks.clone(), min_interval)
+            .filter(move |summary| {
+                future::ready(
+                    threshold_filter
+                        .as_mut()
+                        .is_none_or(|filter| filter.is_significant(summary)),
+                )
+            })
+            .map(move |mut summary| {
+                if let Some(bucket_size) = bucket_size {
+                    group_summary(&mut summary, bucket_size)?;
+                }
+                Ok(summary)
+            })
+            .map_err(|e: GroupingError| Status::invalid_argument(e.to_string()));
         Ok(tonic::Response::new(Box::pin(result_stream)))
     }
+
+    async fn opportunities(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<Self::OpportunitiesStream>, tonic::Status> {
+        let result_stream = broadcast_stream(self.opportunities.subscribe(), "opportunities")
+            .map(Result::<_, tonic::Status>::Ok);
+        Ok(tonic::Response::new(Box::pin(result_stream)))
+    }
+
+    async fn estimate_fill(
+        &self,
+        request: tonic::Request<orderbook::FillRequest>,
+    ) -> Result<tonic::Response<orderbook::FillEstimate>, tonic::Status> {
+        let request = request.into_inner();
+        let side = orderbook::Side::from_i32(request.side)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown side {}", request.side)))?;
+        let target = FillTarget::try_from(request.target)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let estimate = estimate_fill(&self.ticks.borrow(), side, target);
+        Ok(tonic::Response::new(estimate))
+    }
+
+    async fn suggest_route(
+        &self,
+        request: tonic::Request<orderbook::RouteRequest>,
+    ) -> Result<tonic::Response<orderbook::RouteSuggestion>, tonic::Status> {
+        let suggestion = suggest_route(
+            &self.ticks.borrow(),
+            &request.into_inner(),
+            &self.fees,
+            self.fee_adjusted,
+        )
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(tonic::Response::new(suggestion))
+    }
+
+    async fn get_instruments(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<orderbook::Instruments>, tonic::Status> {
+        Ok(tonic::Response::new(self.instruments.to_proto()))
+    }
+
+    async fn get_history(
+        &self,
+        request: tonic::Request<orderbook::HistoryRequest>,
+    ) -> Result<tonic::Response<orderbook::History>, tonic::Status> {
+        let request = request.into_inner();
+        let resolution = orderbook::Resolution::from_i32(request.resolution).ok_or_else(|| {
+            Status::invalid_argument(format!("unknown resolution {}", request.resolution))
+        })?;
+        let bars = self.history.lock().expect("history lock poisoned").bars(
+            resolution,
+            request.since_ms,
+            request.limit as usize,
+        );
+        Ok(tonic::Response::new(orderbook::History {
+            resolution: resolution.into(),
+            bars,
+        }))
+    }
+
+    async fn trades(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<Self::TradesStream>, tonic::Status> {
+        let result_stream =
+            broadcast_stream(self.trades.subscribe(), "trades").map(Result::<_, tonic::Status>::Ok);
+        Ok(tonic::Response::new(Box::pin(result_stream)))
+    }
+
+    async fn bbo(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<Self::BboStream>, tonic::Status> {
+        let result_stream =
+            watch_stream(self.bbo.clone(), Duration::ZERO).map(Result::<_, tonic::Status>::Ok);
+        Ok(tonic::Response::new(Box::pin(result_stream)))
+    }
+
+    async fn book_deltas(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<Self::BookDeltasStream>, tonic::Status> {
+        let mut encoder = DeltaEncoder::default();
+        let result_stream = watch_stream(self.ticks.clone(), Duration::ZERO)
+            .filter_map(move |summary| future::ready(encoder.encode(&summary)))
+            .map(Result::<_, tonic::Status>::Ok);
+        Ok(tonic::Response::new(Box::pin(result_stream)))
+    }
+
+    async fn book_analytics(
+        &self,
+        _request: tonic::Request<orderbook::Empty>,
+    ) -> Result<tonic::Response<Self::BookAnalyticsStream>, tonic::Status> {
+        let result_stream = watch_stream(self.analytics.clone(), Duration::ZERO)
+            .map(Result::<_, tonic::Status>::Ok);
+        Ok(tonic::Response::new(Box::pin(result_stream)))
+    }
+}
+
+/// Smallest time between two summaries requested by a `BookSummary` subscriber.
+pub(crate) fn conflation_interval(
+    request: &orderbook::BookSummaryRequest,
+) -> anyhow::Result<Duration> {
+    let rate_interval = match request.max_updates_per_second {
+        0.0 => Duration::ZERO,
+        rate if rate > 0.0 && rate.is_finite() => Duration::try_from_secs_f64(1.0 / rate)
+            .with_context(|| format!("invalid max_updates_per_second {rate}"))?,
+        rate => bail!("invalid max_updates_per_second {rate}"),
+    };
+    Ok(rate_interval.max(Duration::from_millis(request.min_interval_ms.into())))
+}
+
+/// Streams the changes of a watch channel, at most one every `min_interval`. Changes within the
+/// interval are conflated: only the latest value is sent once the interval is over.
+pub(crate) fn watch_stream<T: Clone + Send + Sync + 'static>(
+    receiver: Receiver<T>,
+    min_interval: Duration,
+) -> impl Stream<Item = T> {
+    stream::unfold(
+        (receiver, None::<Instant>),
+        move |(mut receiver, last_sent)| async move {
+            receiver.changed().await.ok()?;
+            if let Some(last_sent) = last_sent {
+                match last_sent.checked_add(min_interval) {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    // The interval ends past any representable instant: nothing more is sent.
+                    None => future::pending().await,
+                }
+            }
+            let cloned = receiver.borrow_and_update().clone();
+            Some((cloned, (receiver, Some(Instant::now()))))
+        },
+    )
+}
+
+/// Streams the current value of a watch channel, then its changes like [`watch_stream`].
+pub(crate) fn snapshot_stream<T: Clone + Send + Sync + 'static>(
+    mut receiver: Receiver<T>,
+    min_interval: Duration,
+) -> impl Stream<Item = T> {
+    let current = receiver.borrow_and_update().clone();
+    stream::once(future::ready(current)).chain(watch_stream(receiver, min_interval))
+}
+
+/// Streams the messages of a broadcast channel, skipping the ones missed by slow subscribers.
+fn broadcast_stream<T: Clone + Send + 'static>(
+    receiver: broadcast::Receiver<T>,
+    name: &'static str,
+) -> impl Stream<Item = T> {
+    stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => return Some((message, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{} subscriber lagged, skipped {}", name, skipped)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_cors_layer() {
+        assert!(cors_layer(&["https://dashboard.example.com".to_owned()]).is_ok());
+        assert!(cors_layer(&["*".to_owned()]).is_ok());
+        assert!(cors_layer(&["https://bad\norigin".to_owned()]).is_err());
+        assert!(cors_layer(&[]).is_err());
+    }
+
+    #[test]
+    fn test_conflation_interval() {
+        let request = |max_updates_per_second, min_interval_ms| orderbook::BookSummaryRequest {
+            max_updates_per_second,
+            min_interval_ms,
+            ..Default::default()
+        };
+        assert_eq!(
+            Duration::ZERO,
+            conflation_interval(&request(0.0, 0)).unwrap()
+        );
+        assert_eq!(
+            Duration::from_millis(250),
+            conflation_interval(&request(4.0, 100)).unwrap()
+        );
+        assert_eq!(
+            Duration::from_millis(500),
+            conflation_interval(&request(4.0, 500)).unwrap()
+        );
+        assert!(conflation_interval(&request(1e-30, 0)).is_err());
+        assert!(conflation_interval(&request(f64::INFINITY, 0)).is_err());
+        assert!(conflation_interval(&request(-1.0, 0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch_stream_unbounded_interval() {
+        let (sender, receiver) = tokio::sync::watch::channel(0);
+        let mut stream = Box::pin(watch_stream(receiver, Duration::MAX));
+        sender.send(1).unwrap();
+        assert_eq!(Some(1), stream.next().await);
+        sender.send(2).unwrap();
+        let next = time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    fn service() -> OrderBookAggregatorService {
+        let summaries = tokio::sync::watch::channel(orderbook::Summary::default()).1;
+        let bbo = tokio::sync::watch::channel(orderbook::BestBidOffer::default()).1;
+        let analytics = tokio::sync::watch::channel(orderbook::Analytics::default()).1;
+        OrderBookAggregatorService {
+            ticks: summaries,
+            bbo,
+            analytics,
+            opportunities: broadcast::channel(1).0,
+            trades: broadcast::channel(1).0,
+            instruments: Arc::new(InstrumentRegistry::default()),
+            history: Arc::new(Mutex::new(History::new(&Default::default()))),
+            fees: Default::default(),
+            fee_adjusted: false,
+        }
+    }
+
+    fn server_config(bind: &[&str]) -> configuration::Server {
+        configuration::Server {
+            port: 5000,
+            bind: bind
+                .iter()
+                .map(|address| BindAddress::from(address.to_string()))
+                .collect(),
+            grpc_web: false,
+            allowed_origins: vec![],
+            tls: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_error() {
+        let result = grpc_server(
+            service(),
+            CancellationToken::new(),
+            server_config(&["not an address"]),
+        )
+        .await;
+        assert_eq!("binding not an address", result.unwrap_err().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("grpc-{}.sock", std::process::id()));
+        let stop_signal = CancellationToken::new();
+        let config = server_config(&["127.0.0.1:0", &format!("unix:{}", path.display())]);
+        let server = tokio::spawn(grpc_server(service(), stop_signal.clone(), config));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while UnixStream::connect(&path).await.is_err() {
+            assert!(
+                Instant::now() < deadline,
+                "server not listening on {path:?}"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        stop_signal.cancel();
+        server.await.unwrap().unwrap();
+        fs::remove_file(path).unwrap();
+    }
 }
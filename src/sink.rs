@@ -0,0 +1,231 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use log::info;
+use prost::Message;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::configuration::{
+    self, FileSinkConfig, HttpSinkConfig, UdpSinkConfig, WebSocketSinkConfig,
+};
+use crate::file_sink::file_sink;
+use crate::http_api::http_api;
+use crate::orderbook::Summary;
+use crate::server::{grpc_server, OrderBookAggregatorService};
+use crate::symbols::SymbolAliases;
+use crate::websocket::websocket_gateway;
+
+/// A consumer of the aggregated summaries. Every sink runs in its own task and is restarted with
+/// backoff when it fails, without affecting the others.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Name of the sink task in the logs.
+    fn name(&self) -> &'static str;
+
+    /// Consumes the summaries until the channel is closed.
+    async fn run(&self, summaries: watch::Receiver<Summary>) -> anyhow::Result<()>;
+}
+
+/// Serves the summaries, and the other pipeline channels, with the gRPC service.
+pub struct GrpcSink {
+    service: OrderBookAggregatorService,
+    config: configuration::Server,
+    stop_signal: CancellationToken,
+}
+
+impl GrpcSink {
+    pub fn new(
+        service: OrderBookAggregatorService,
+        config: configuration::Server,
+        stop_signal: CancellationToken,
+    ) -> Self {
+        Self {
+            service,
+            config,
+            stop_signal,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for GrpcSink {
+    fn name(&self) -> &'static str {
+        "grpc_server"
+    }
+
+    async fn run(&self, summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        let service = OrderBookAggregatorService {
+            ticks: summaries,
+            ..self.service.clone()
+        };
+        grpc_server(service, self.stop_signal.clone(), self.config.clone()).await
+    }
+}
+
+/// Writes the summaries to daily CSV and Parquet files.
+pub struct FileSink {
+    config: FileSinkConfig,
+    levels: usize,
+}
+
+impl FileSink {
+    pub fn new(config: FileSinkConfig, levels: usize) -> Self {
+        Self { config, levels }
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file_sink"
+    }
+
+    async fn run(&self, summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        file_sink(summaries, self.config.clone(), self.levels).await
+    }
+}
+
+/// Prints every summary to the standard output as a line of JSON.
+pub struct StdoutSink;
+
+#[async_trait]
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout_sink"
+    }
+
+    async fn run(&self, mut summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        let mut stdout = io::stdout();
+        while summaries.changed().await.is_ok() {
+            let mut line = serde_json::to_vec(&*summaries.borrow_and_update())?;
+            line.push(b'\n');
+            stdout.write_all(&line).await?;
+            stdout.flush().await?;
+        }
+        info!("summaries channel closed. Exiting");
+        Ok(())
+    }
+}
+
+/// Sends every summary, encoded as protobuf, in a datagram to a UDP address, usually a multicast
+/// group.
+pub struct UdpSink {
+    config: UdpSinkConfig,
+}
+
+impl UdpSink {
+    pub fn new(config: UdpSinkConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Sink for UdpSink {
+    fn name(&self) -> &'static str {
+        "udp_sink"
+    }
+
+    async fn run(&self, mut summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        let address = self.config.address;
+        let unspecified = match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+        if address.is_ipv4() {
+            socket.set_multicast_ttl_v4(self.config.ttl)?;
+        }
+        while summaries.changed().await.is_ok() {
+            let datagram = summaries.borrow_and_update().encode_to_vec();
+            socket
+                .send_to(&datagram, address)
+                .await
+                .with_context(|| format!("sending summary to {address}"))?;
+        }
+        info!("summaries channel closed. Exiting");
+        Ok(())
+    }
+}
+
+/// Streams the summaries as JSON to the WebSocket clients subscribed to `symbol`.
+pub struct WebSocketSink {
+    config: WebSocketSinkConfig,
+    symbol: SymbolAliases,
+}
+
+impl WebSocketSink {
+    pub fn new(config: WebSocketSinkConfig, symbol: SymbolAliases) -> Self {
+        Self { config, symbol }
+    }
+}
+
+#[async_trait]
+impl Sink for WebSocketSink {
+    fn name(&self) -> &'static str {
+        "websocket_gateway"
+    }
+
+    async fn run(&self, summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(self.config.bind)
+            .await
+            .with_context(|| format!("binding {}", self.config.bind))?;
+        websocket_gateway(listener, summaries, self.symbol.clone()).await
+    }
+}
+
+/// Serves the current summary and a stream of them over HTTP.
+pub struct HttpSink {
+    config: HttpSinkConfig,
+    symbol: SymbolAliases,
+}
+
+impl HttpSink {
+    pub fn new(config: HttpSinkConfig, symbol: SymbolAliases) -> Self {
+        Self { config, symbol }
+    }
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http_api"
+    }
+
+    async fn run(&self, summaries: watch::Receiver<Summary>) -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind(self.config.bind)
+            .with_context(|| format!("binding {}", self.config.bind))?;
+        http_api(listener, summaries, self.symbol.clone()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_sink() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sink = UdpSink::new(UdpSinkConfig {
+            address: receiver.local_addr().unwrap(),
+            ttl: 1,
+        });
+        let (sender, summaries) = watch::channel(Summary::default());
+        let task = tokio::spawn(async move { sink.run(summaries).await });
+        let summary = Summary {
+            spread: 1.5,
+            ..Default::default()
+        };
+        sender.send(summary.clone()).unwrap();
+
+        let mut buffer = [0; 1024];
+        let length = receiver.recv(&mut buffer).await.unwrap();
+        assert_eq!(summary, Summary::decode(&buffer[..length]).unwrap());
+        drop(sender);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Canonical identifier of an instrument, written `BASE/QUOTE`, e.g. `ETH/BTC`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instrument {
+    pub base: String,
+    pub quote: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SymbolError {
+    #[error("symbol {0} is not in BASE/QUOTE form")]
+    NotCanonical(String),
+}
+
+impl FromStr for Instrument {
+    type Err = SymbolError;
+
+    fn from_str(symbol: &str) -> Result<Self, Self::Err> {
+        match symbol.split_once('/') {
+            Some((base, quote))
+                if !base.is_empty() && !quote.is_empty() && !quote.contains('/') =>
+            {
+                Ok(Self {
+                    base: base.trim().to_uppercase(),
+                    quote: quote.trim().to_uppercase(),
+                })
+            }
+            _ => Err(SymbolError::NotCanonical(symbol.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Instrument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolCase {
+    #[default]
+    Lower,
+    Upper,
+}
+
+/// How an exchange writes the symbol of a canonical instrument.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct SymbolRules {
+    /// Written between the base and quote assets.
+    #[serde(default)]
+    pub separator: String,
+    #[serde(default)]
+    pub case: SymbolCase,
+    /// Exchange names of the assets that differ from the canonical ones, e.g. `BTC = "XBT"`.
+    #[serde(default)]
+    pub assets: HashMap<String, String>,
+    /// Exchange symbols by canonical symbol. They take precedence over the rules.
+    #[serde(default)]
+    pub overrides: HashMap<String, String>,
+}
+
+impl SymbolRules {
+    /// Lowercase concatenated symbols, like `ethbtc`, used by Binance and Bitstamp.
+    pub fn concatenated() -> Self {
+        Self::default()
+    }
+
+    /// `ETH/XBT` style symbols.
+    pub fn kraken() -> Self {
+        Self {
+            separator: "/".to_owned(),
+            case: SymbolCase::Upper,
+            assets: HashMap::from([
+                ("BTC".to_owned(), "XBT".to_owned()),
+                ("DOGE".to_owned(), "XDG".to_owned()),
+            ]),
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// `ETH-BTC` style symbols, used by Coinbase and OKX.
+    pub fn dashed() -> Self {
+        Self {
+            separator: "-".to_owned(),
+            case: SymbolCase::Upper,
+            ..Self::default()
+        }
+    }
+
+    /// Symbol of `symbol` on the exchange. Symbols not in `BASE/QUOTE` form are taken as already
+    /// written for the exchange.
+    pub fn exchange_symbol(&self, symbol: &str) -> String {
+        if let Some(exchange_symbol) = get_ignore_case(&self.overrides, symbol) {
+            return exchange_symbol.clone();
+        }
+        match symbol.parse::<Instrument>() {
+            Ok(instrument) => {
+                let asset = |asset: &String| {
+                    get_ignore_case(&self.assets, asset)
+                        .unwrap_or(asset)
+                        .clone()
+                };
+                let exchange_symbol = format!(
+                    "{}{}{}",
+                    asset(&instrument.base),
+                    self.separator,
+                    asset(&instrument.quote)
+                );
+                match self.case {
+                    SymbolCase::Lower => exchange_symbol.to_lowercase(),
+                    SymbolCase::Upper => exchange_symbol.to_uppercase(),
+                }
+            }
+            Err(_) => symbol.to_owned(),
+        }
+    }
+}
+
+/// Names clients may use for the aggregated symbol: as configured, canonical `BASE/QUOTE`, the
+/// URL friendly `BASE-QUOTE`, or as written by any of the exchanges.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolAliases {
+    symbol: String,
+    rules: Vec<SymbolRules>,
+}
+
+impl SymbolAliases {
+    pub fn new(symbol: String, rules: Vec<SymbolRules>) -> Self {
+        Self { symbol, rules }
+    }
+
+    /// The aggregated symbol as configured.
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn matches(&self, requested: &str) -> bool {
+        let canonical = requested.replace('-', "/");
+        [requested, canonical.as_str()].iter().any(|candidate| {
+            candidate.eq_ignore_ascii_case(&self.symbol)
+                || self.rules.iter().any(|rules| {
+                    rules
+                        .exchange_symbol(candidate)
+                        .eq_ignore_ascii_case(&rules.exchange_symbol(&self.symbol))
+                })
+        })
+    }
+}
+
+// the config crate lowercases the keys of the tables
+pub(crate) fn get_ignore_case<'a, V>(map: &'a HashMap<String, V>, key: &str) -> Option<&'a V> {
+    map.get(key).or_else(|| {
+        map.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_exchange_symbols() {
+        assert_eq!(
+            "ethbtc",
+            SymbolRules::concatenated().exchange_symbol("ETH/BTC")
+        );
+        assert_eq!(
+            "ethbtc",
+            SymbolRules::concatenated().exchange_symbol("ethbtc")
+        );
+        assert_eq!("ETH/XBT", SymbolRules::kraken().exchange_symbol("eth/btc"));
+        assert_eq!("ETH-BTC", SymbolRules::dashed().exchange_symbol("ETH/BTC"));
+        let rules = SymbolRules {
+            overrides: HashMap::from([("ETH/BTC".to_owned(), "ethxbt".to_owned())]),
+            ..SymbolRules::concatenated()
+        };
+        assert_eq!("ethxbt", rules.exchange_symbol("ETH/BTC"));
+        assert_eq!("ltcbtc", rules.exchange_symbol("LTC/BTC"));
+    }
+
+    #[test]
+    fn test_parse_instrument() {
+        assert_eq!(
+            Ok(Instrument {
+                base: "ETH".to_owned(),
+                quote: "BTC".to_owned(),
+            }),
+            "eth/btc".parse()
+        );
+        assert!("ethbtc".parse::<Instrument>().is_err());
+        assert!("ETH/".parse::<Instrument>().is_err());
+    }
+
+    #[test]
+    fn test_symbol_aliases() {
+        let bitstamp = SymbolRules {
+            overrides: HashMap::from([("ETH/BTC".to_owned(), "ethxbt".to_owned())]),
+            ..SymbolRules::concatenated()
+        };
+        let aliases = SymbolAliases::new(
+            "ETH/BTC".to_owned(),
+            vec![SymbolRules::concatenated(), bitstamp],
+        );
+        for alias in ["ethbtc", "ETHBTC", "eth/btc", "eth-btc", "ethxbt"] {
+            assert!(aliases.matches(alias), "{alias}");
+        }
+        assert!(!aliases.matches("ltcbtc"));
+        assert!(!aliases.matches("LTC-BTC"));
+        let aliases = SymbolAliases::new("ethbtc".to_owned(), vec![SymbolRules::concatenated()]);
+        assert!(aliases.matches("ETH-BTC"));
+    }
+}
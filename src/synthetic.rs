@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::aggregator::{ExchangeOrders, Level};
+use crate::configuration::{ScaleConfig, SyntheticConfig};
+use crate::orderbook::LevelSource;
+
+/// Keeps the latest legs received from each exchange and the synthetic book implied by them.
+#[derive(Debug)]
+pub struct SyntheticBooks {
+    symbol: String,
+    config: SyntheticConfig,
+    max_levels: usize,
+    scale: ScaleConfig,
+    base_legs: HashMap<String, ExchangeOrders>,
+    quote_legs: HashMap<String, ExchangeOrders>,
+    books: HashMap<String, ExchangeOrders>,
+}
+
+impl SyntheticBooks {
+    pub fn new(
+        symbol: String,
+        config: SyntheticConfig,
+        max_levels: usize,
+        scale: ScaleConfig,
+    ) -> Self {
+        Self {
+            symbol,
+            config,
+            max_levels,
+            scale,
+            base_legs: HashMap::new(),
+            quote_legs: HashMap::new(),
+            books: HashMap::new(),
+        }
+    }
+
+    /// Stores a leg and recalculates the synthetic book of its exchange. Returns `false` if the
+    /// orders are not for one of the legs.
+    pub fn update(&mut self, orders: ExchangeOrders) -> bool {
+        let exchange_name = orders.exchange_name.clone();
+        if orders.symbol == self.config.base_leg {
+            self.base_legs.insert(exchange_name.clone(), orders);
+        } else if orders.symbol == self.config.quote_leg {
+            self.quote_legs.insert(exchange_name.clone(), orders);
+        } else {
+            return false;
+        }
+        if let (Some(base), Some(quote)) = (
+            self.base_legs.get(&exchange_name),
+            self.quote_legs.get(&exchange_name),
+        ) {
+            let book = implied_book(&self.symbol, base, quote, self.max_levels, &self.scale);
+            self.books.insert(exchange_name, book);
+        }
+        true
+    }
+
+    pub fn books(&self) -> impl Iterator<Item = &ExchangeOrders> {
+        self.books.values()
+    }
+}
+
+/// Implies the book of `base`/`quote` from the books of both assets against a common one.
+/// Selling the base asset means selling it on the base leg bids and buying the quote asset on the
+/// quote leg asks. Buying it means selling the quote asset on the quote leg bids and buying the
+/// base asset on the base leg asks. Prices are rounded against us and amounts down to `scale`.
+pub fn implied_book(
+    symbol: &str,
+    base: &ExchangeOrders,
+    quote: &ExchangeOrders,
+    max_levels: usize,
+    scale: &ScaleConfig,
+) -> ExchangeOrders {
+    ExchangeOrders {
+        exchange_name: base.exchange_name.clone(),
+        symbol: symbol.to_owned(),
+        asks: implied_levels(
+            &base.asks,
+            &quote.bids,
+            max_levels,
+            scale,
+            RoundingStrategy::AwayFromZero,
+        ),
+        bids: implied_levels(
+            &base.bids,
+            &quote.asks,
+            max_levels,
+            scale,
+            RoundingStrategy::ToZero,
+        ),
+    }
+}
+
+/// Matches the base and quote leg levels by the amount of the common asset they trade. Both sides
+/// must be sorted best first, which keeps the implied levels sorted as well.
+fn implied_levels(
+    base_levels: &[Level],
+    quote_levels: &[Level],
+    max_levels: usize,
+    scale: &ScaleConfig,
+    price_rounding: RoundingStrategy,
+) -> Vec<Level> {
+    let mut result = Vec::with_capacity(max_levels);
+    let mut base_iter = base_levels.iter();
+    let mut quote_iter = quote_levels.iter();
+    let (Some(mut base), Some(mut quote)) = (base_iter.next(), quote_iter.next()) else {
+        return result;
+    };
+    let mut base_left = base.amount * base.price;
+    let mut quote_left = quote.amount * quote.price;
+    while result.len() < max_levels {
+        let common_amount = base_left.min(quote_left);
+        let price = base.price.checked_div(quote.price);
+        let amount = common_amount.checked_div(base.price);
+        if let (Some(price), Some(amount)) = (price, amount) {
+            let amount = amount.round_dp_with_strategy(scale.quantity, RoundingStrategy::ToZero);
+            if amount > Decimal::ZERO {
+                result.push(Level {
+                    price: price.round_dp_with_strategy(scale.price, price_rounding),
+                    amount,
+                    exchange_name: base.exchange_name.clone(),
+                    source: LevelSource::Synthetic,
+                });
+            }
+        }
+        base_left -= common_amount;
+        quote_left -= common_amount;
+        if base_left <= Decimal::ZERO {
+            match base_iter.next() {
+                Some(next) => {
+                    base = next;
+                    base_left = base.amount * base.price;
+                }
+                None => break,
+            }
+        }
+        if quote_left <= Decimal::ZERO {
+            match quote_iter.next() {
+                Some(next) => {
+                    quote = next;
+                    quote_left = quote.amount * quote.price;
+                }
+                None => break,
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn level(price: f64, amount: f64) -> Level {
+        Level {
+            price: price.try_into().unwrap(),
+            amount: amount.try_into().unwrap(),
+            exchange_name: "a".to_owned(),
+            source: LevelSource::Direct,
+        }
+    }
+
+    fn orders(symbol: &str, bids: Vec<Level>, asks: Vec<Level>) -> ExchangeOrders {
+        ExchangeOrders {
+            exchange_name: "a".to_owned(),
+            symbol: symbol.to_owned(),
+            asks,
+            bids,
+        }
+    }
+
+    fn prices_and_amounts(levels: &[Level]) -> Vec<(Decimal, Decimal)> {
+        levels.iter().map(|l| (l.price, l.amount)).collect()
+    }
+
+    fn decimals(values: &[(&str, &str)]) -> Vec<(Decimal, Decimal)> {
+        values
+            .iter()
+            .map(|(price, amount)| (price.parse().unwrap(), amount.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_implied_book() {
+        // ETH/USDT and BTC/USDT legs implying ETH/BTC
+        let base = orders(
+            "ethusdt",
+            vec![level(2000.0, 1.0), level(1990.0, 2.0)],
+            vec![level(2010.0, 1.0)],
+        );
+        let quote = orders(
+            "btcusdt",
+            vec![level(40000.0, 1.0)],
+            vec![level(40000.0, 0.025), level(40100.0, 1.0)],
+        );
+        let book = implied_book("ethbtc", &base, &quote, 10, &ScaleConfig::default());
+        // 1000 USDT at 40000 is half of the first ETH bid, the other half goes to the next BTC ask.
+        // Bid prices are rounded down.
+        assert_eq!(
+            decimals(&[("0.05", "0.5"), ("0.04987531", "0.5"), ("0.04962593", "2")]),
+            prices_and_amounts(&book.bids)
+        );
+        assert_eq!(
+            decimals(&[("0.05025", "1")]),
+            prices_and_amounts(&book.asks)
+        );
+        assert!(book
+            .bids
+            .iter()
+            .all(|level| level.source == LevelSource::Synthetic));
+    }
+
+    #[test]
+    fn test_books_wait_for_both_legs() {
+        let config = SyntheticConfig {
+            base_leg: "ethusdt".to_owned(),
+            quote_leg: "btcusdt".to_owned(),
+        };
+        let mut books =
+            SyntheticBooks::new("ethbtc".to_owned(), config, 10, ScaleConfig::default());
+        assert!(books.update(orders("ethusdt", vec![level(2000.0, 1.0)], vec![])));
+        assert_eq!(0, books.books().count());
+        assert!(!books.update(orders("ethbtc", vec![], vec![])));
+        assert!(books.update(orders("btcusdt", vec![], vec![level(40000.0, 1.0)])));
+        let book = books.books().next().unwrap();
+        assert_eq!("ethbtc", book.symbol);
+        assert_eq!(decimals(&[("0.05", "1")]), prices_and_amounts(&book.bids));
+    }
+}
@@ -0,0 +1,165 @@
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::decimal::parse_or;
+use crate::orderbook::{self, Summary};
+
+/// Minimum moves of the best prices or the spread for a summary to be worth sending.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChangeThreshold {
+    /// Zero when the best prices are not watched.
+    pub best_price: Decimal,
+    /// Zero when the spread is not watched.
+    pub spread: Decimal,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ThresholdError {
+    #[error("threshold moves can't be negative, got {0}")]
+    Negative(f64),
+    #[error("at least one threshold move must be set")]
+    Empty,
+}
+
+impl TryFrom<orderbook::Threshold> for ChangeThreshold {
+    type Error = ThresholdError;
+
+    fn try_from(value: orderbook::Threshold) -> Result<Self, Self::Error> {
+        let to_decimal = |amount: f64| {
+            Decimal::try_from(amount)
+                .ok()
+                .filter(|amount| !amount.is_sign_negative())
+                .ok_or(ThresholdError::Negative(amount))
+        };
+        let threshold = Self {
+            best_price: to_decimal(value.best_price_move)?,
+            spread: to_decimal(value.spread_move)?,
+        };
+        if threshold.best_price.is_zero() && threshold.spread.is_zero() {
+            return Err(ThresholdError::Empty);
+        }
+        Ok(threshold)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TopOfBook {
+    best_bid: Option<Decimal>,
+    best_ask: Option<Decimal>,
+    spread: Decimal,
+}
+
+/// Lets through the summaries that moved more than the threshold since the last one let through.
+#[derive(Debug)]
+pub struct ThresholdFilter {
+    threshold: ChangeThreshold,
+    last: Option<TopOfBook>,
+}
+
+impl ThresholdFilter {
+    pub fn new(threshold: ChangeThreshold) -> Self {
+        Self {
+            threshold,
+            last: None,
+        }
+    }
+
+    pub fn is_significant(&mut self, summary: &Summary) -> bool {
+        let top = TopOfBook::from_summary(summary);
+        let significant = match &self.last {
+            None => true,
+            Some(last) => {
+                let best_price = self.threshold.best_price;
+                let best_price_moved = !best_price.is_zero()
+                    && (moved(last.best_bid, top.best_bid, best_price)
+                        || moved(last.best_ask, top.best_ask, best_price));
+                let spread_moved = !self.threshold.spread.is_zero()
+                    && (top.spread - last.spread).abs() > self.threshold.spread;
+                best_price_moved || spread_moved
+            }
+        };
+        if significant {
+            self.last = Some(top);
+        }
+        significant
+    }
+}
+
+/// A best price appearing or disappearing is always a move.
+fn moved(previous: Option<Decimal>, current: Option<Decimal>, threshold: Decimal) -> bool {
+    match (previous, current) {
+        (Some(previous), Some(current)) => (current - previous).abs() > threshold,
+        (previous, current) => previous != current,
+    }
+}
+
+impl TopOfBook {
+    fn from_summary(summary: &Summary) -> Self {
+        let price = |level: &orderbook::Level| parse_or(&level.price_decimal, level.price);
+        Self {
+            best_bid: summary.bids.first().map(price),
+            best_ask: summary.asks.first().map(price),
+            spread: parse_or(&summary.spread_decimal, summary.spread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn summary(best_bid: &str, best_ask: &str) -> Summary {
+        let level = |price: &str| orderbook::Level {
+            price_decimal: price.to_owned(),
+            ..Default::default()
+        };
+        let spread = best_ask.parse::<Decimal>().unwrap() - best_bid.parse::<Decimal>().unwrap();
+        Summary {
+            bids: vec![level(best_bid)],
+            asks: vec![level(best_ask)],
+            spread_decimal: spread.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_best_price_threshold() {
+        let threshold = ChangeThreshold::try_from(orderbook::Threshold {
+            best_price_move: 0.5,
+            spread_move: 0.0,
+        })
+        .unwrap();
+        let mut filter = ThresholdFilter::new(threshold);
+        assert!(filter.is_significant(&summary("10", "11")));
+        assert!(!filter.is_significant(&summary("10.5", "11")));
+        assert!(filter.is_significant(&summary("10.6", "11")));
+        assert!(!filter.is_significant(&summary("10.6", "11.5")));
+        assert!(filter.is_significant(&summary("10.6", "11.6")));
+        assert!(filter.is_significant(&Summary::default()));
+    }
+
+    #[test]
+    fn test_spread_threshold() {
+        let threshold = ChangeThreshold::try_from(orderbook::Threshold {
+            best_price_move: 0.0,
+            spread_move: 0.2,
+        })
+        .unwrap();
+        let mut filter = ThresholdFilter::new(threshold);
+        assert!(filter.is_significant(&summary("10", "11")));
+        assert!(!filter.is_significant(&summary("12", "13")));
+        assert!(filter.is_significant(&summary("12", "13.3")));
+        assert_eq!(
+            Err(ThresholdError::Empty),
+            ChangeThreshold::try_from(orderbook::Threshold::default())
+        );
+        assert_eq!(
+            Err(ThresholdError::Negative(-1.0)),
+            ChangeThreshold::try_from(orderbook::Threshold {
+                best_price_move: -1.0,
+                spread_move: 0.0,
+            })
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use futures::{stream, Stream};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::configuration::TlsConfig;
+use crate::server::accept_error;
+
+/// Connections waiting to be served once their handshake is done.
+const HANDSHAKES_CHANNEL_SIZE: usize = 64;
+
+/// Time given to a client to complete its handshake before it is disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TLS connections on `listener`. The certificates are checked for changes every
+/// `reload_interval`, and the new ones are used for the following connections. HTTP/1.1 is
+/// negotiated besides HTTP/2 for `grpc_web` browser clients.
+pub(crate) fn tls_incoming(
+    listener: TcpListener,
+    config: TlsConfig,
+    grpc_web: bool,
+) -> anyhow::Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    let mut certificates = Certificates::load(&config, grpc_web)?;
+    let (sender, receiver) = mpsc::channel(HANDSHAKES_CHANNEL_SIZE);
+    tokio::spawn(async move {
+        let mut reload = time::interval(config.reload_interval);
+        loop {
+            tokio::select! {
+                _ = reload.tick() => certificates.reload_if_changed(&config, grpc_web),
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            accept_error(e).await;
+                            continue;
+                        }
+                    };
+                    let acceptor = certificates.acceptor.clone();
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let _ = sender.send(Ok(stream)).await;
+                            }
+                            Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", peer, e),
+                            Err(_) => warn!("TLS handshake with {} timed out", peer),
+                        }
+                    });
+                }
+                _ = sender.closed() => break,
+            }
+        }
+    });
+    Ok(stream::unfold(receiver, |mut receiver| async move {
+        let stream = receiver.recv().await?;
+        Some((stream, receiver))
+    }))
+}
+
+/// The acceptor built from the certificate files, and when they were last modified.
+struct Certificates {
+    acceptor: TlsAcceptor,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl Certificates {
+    fn load(config: &TlsConfig, grpc_web: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config(config, grpc_web)?)),
+            modified: modified_times(config),
+        })
+    }
+
+    /// Keeps the current certificates when the new ones can't be loaded, for instance when
+    /// the files are being replaced.
+    fn reload_if_changed(&mut self, config: &TlsConfig, grpc_web: bool) {
+        if modified_times(config) == self.modified {
+            return;
+        }
+        match Self::load(config, grpc_web) {
+            Ok(certificates) => {
+                info!("Reloaded the TLS certificates");
+                *self = certificates;
+            }
+            Err(e) => warn!("Error reloading the TLS certificates: {:#}", e),
+        }
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&config.cert),
+        Some(&config.key),
+        config.client_ca.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+/// Server configuration for HTTP/2, and HTTP/1.1 with `grpc_web`, requiring client certificates
+/// signed by `client_ca` if set.
+fn server_config(config: &TlsConfig, grpc_web: bool) -> anyhow::Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots.add(&certificate)?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config =
+        builder.with_single_cert(read_certificates(&config.cert)?, read_key(&config.key)?)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    if grpc_web {
+        server_config.alpn_protocols.push(b"http/1.1".to_vec());
+    }
+    Ok(server_config)
+}
+
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(open(path)?);
+    let certificates: Vec<_> = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("reading {}", path.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certificates.is_empty() {
+        bail!("no certificate found in {}", path.display());
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("reading {}", path.display()))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in {}", path.display()),
+        }
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<File> {
+    File::open(path).with_context(|| format!("opening {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_certificates() {
+        let config = TlsConfig {
+            cert: PathBuf::from("missing/server.pem"),
+            key: PathBuf::from("missing/server.key"),
+            client_ca: None,
+            reload_interval: Duration::from_secs(60),
+        };
+        let error = server_config(&config, false).unwrap_err();
+        assert_eq!("opening missing/server.pem", error.to_string());
+        assert_eq!(vec![None, None], modified_times(&config));
+    }
+}
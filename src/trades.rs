@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use rust_decimal::Decimal;
+
+use crate::common::unix_millis;
+use crate::configuration::ScaleConfig;
+use crate::decimal::{format_scaled, to_f64};
+use crate::orderbook::{self, Side, Summary};
+
+/// A trade normalized from the exchange messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub exchange_name: String,
+    pub symbol: String,
+    pub price: Decimal,
+    pub amount: Decimal,
+    /// Side of the taker.
+    pub side: Side,
+    pub trade_id: String,
+    /// When the exchange matched the trade.
+    pub traded_at: SystemTime,
+    pub received_at: SystemTime,
+}
+
+impl Trade {
+    pub fn to_proto(&self, scale: &ScaleConfig) -> orderbook::Trade {
+        orderbook::Trade {
+            exchange: self.exchange_name.clone(),
+            symbol: self.symbol.clone(),
+            price: to_f64(self.price),
+            amount: to_f64(self.amount),
+            side: self.side.into(),
+            price_decimal: format_scaled(self.price, scale.price),
+            amount_decimal: format_scaled(self.amount, scale.quantity),
+            trade_id: self.trade_id.clone(),
+            traded_at_ms: unix_millis(self.traded_at),
+            received_at_ms: unix_millis(self.received_at),
+        }
+    }
+}
+
+/// Last trade price and the volume traded over a rolling window, across exchanges.
+#[derive(Debug)]
+pub struct TradeStats {
+    window: Duration,
+    trades: VecDeque<(SystemTime, Decimal)>,
+    volume: Decimal,
+    last_price: Option<Decimal>,
+}
+
+impl TradeStats {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            trades: VecDeque::new(),
+            volume: Decimal::ZERO,
+            last_price: None,
+        }
+    }
+
+    /// Trades are expected in the order they are received.
+    pub fn update(&mut self, trade: &Trade) {
+        self.last_price = Some(trade.price);
+        self.volume += trade.amount;
+        self.trades.push_back((trade.received_at, trade.amount));
+        self.expire(trade.received_at);
+    }
+
+    pub fn last_price(&self) -> Option<Decimal> {
+        self.last_price
+    }
+
+    /// Amount traded during the window ending at `now`.
+    pub fn volume(&mut self, now: SystemTime) -> Decimal {
+        self.expire(now);
+        self.volume
+    }
+
+    /// Sets the last trade price and the rolling volume of the summary, if there were trades.
+    pub fn fill_summary(&mut self, summary: &mut Summary, now: SystemTime, scale: &ScaleConfig) {
+        let Some(last_price) = self.last_price else {
+            return;
+        };
+        let volume = self.volume(now);
+        summary.last_trade_price = to_f64(last_price);
+        summary.last_trade_price_decimal = format_scaled(last_price, scale.price);
+        summary.trade_volume = to_f64(volume);
+        summary.trade_volume_decimal = format_scaled(volume, scale.quantity);
+    }
+
+    fn expire(&mut self, now: SystemTime) {
+        let Some(start) = now.checked_sub(self.window) else {
+            return;
+        };
+        while let Some(&(received_at, amount)) = self.trades.front() {
+            if received_at > start {
+                break;
+            }
+            self.volume -= amount;
+            self.trades.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_rolling_volume() {
+        let start = SystemTime::now();
+        let trade = |price: &str, amount: &str, received_after_ms: u64| Trade {
+            exchange_name: "a".to_owned(),
+            symbol: "ab".to_owned(),
+            price: price.parse().unwrap(),
+            amount: amount.parse().unwrap(),
+            side: Side::Buy,
+            trade_id: "1".to_owned(),
+            traded_at: start,
+            received_at: start + Duration::from_millis(received_after_ms),
+        };
+        let mut stats = TradeStats::new(Duration::from_secs(1));
+        assert_eq!(None, stats.last_price());
+        stats.update(&trade("10.5", "1.5", 0));
+        stats.update(&trade("10.4", "2", 500));
+        assert_eq!(Some("10.4".parse().unwrap()), stats.last_price());
+        assert_eq!(
+            "3.5".parse::<Decimal>().unwrap(),
+            stats.volume(start + Duration::from_millis(900))
+        );
+        stats.update(&trade("10.6", "0.25", 1200));
+        assert_eq!(
+            "2.25".parse::<Decimal>().unwrap(),
+            stats.volume(start + Duration::from_millis(1200))
+        );
+        assert_eq!(
+            Decimal::ZERO,
+            stats.volume(start + Duration::from_millis(2500))
+        );
+        assert_eq!(Some("10.6".parse().unwrap()), stats.last_price());
+    }
+}
@@ -0,0 +1,268 @@
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::decimal::{format_scaled, parse_or, to_f64};
+use crate::orderbook::{self, fill_request::Target, ExchangeFill, FillEstimate, Side, Summary};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillTarget {
+    Quantity(Decimal),
+    Notional(Decimal),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum FillError {
+    #[error("fill target not set")]
+    MissingTarget,
+    #[error("fill target must be a positive decimal, got {0}")]
+    InvalidTarget(f64),
+}
+
+impl TryFrom<Option<Target>> for FillTarget {
+    type Error = FillError;
+
+    fn try_from(value: Option<Target>) -> Result<Self, Self::Error> {
+        let (size, target): (f64, fn(Decimal) -> FillTarget) =
+            match value.ok_or(FillError::MissingTarget)? {
+                Target::Quantity(quantity) => (quantity, FillTarget::Quantity),
+                Target::Notional(notional) => (notional, FillTarget::Notional),
+            };
+        match Decimal::try_from(size) {
+            Ok(decimal) if size.is_finite() && decimal > Decimal::ZERO => Ok(target(decimal)),
+            _ => Err(FillError::InvalidTarget(size)),
+        }
+    }
+}
+
+/// Returns the levels a market order on `side` would consume: asks for buys and bids for sells.
+pub fn levels_for_side(summary: &Summary, side: Side) -> &[orderbook::Level] {
+    match side {
+        Side::Buy => &summary.asks,
+        Side::Sell => &summary.bids,
+    }
+}
+
+/// Estimates the fill of a market order on `side` walking the aggregated book, best level first,
+/// until `target` is reached or the book runs out of levels. Quantities and notionals are summed
+/// as exact decimals, so a target matching the book depth is filled exactly.
+pub fn estimate_fill(summary: &Summary, side: Side, target: FillTarget) -> FillEstimate {
+    let levels = levels_for_side(summary, side);
+    let target_size = match target {
+        FillTarget::Quantity(size) | FillTarget::Notional(size) => size,
+    };
+    let mut filled_quantity = Decimal::ZERO;
+    let mut filled_notional = Decimal::ZERO;
+    let mut worst_price = Decimal::ZERO;
+    let mut exchanges: Vec<(&str, Decimal, Decimal)> = vec![];
+    for level in levels {
+        let price = parse_or(&level.price_decimal, level.price);
+        let amount = parse_or(&level.amount_decimal, level.amount);
+        let (quantity, notional) = match target {
+            FillTarget::Quantity(_) => {
+                let quantity = amount.min(target_size - filled_quantity);
+                (quantity, quantity * price)
+            }
+            FillTarget::Notional(_) => {
+                let remaining = target_size - filled_notional;
+                if amount * price > remaining {
+                    (remaining / price, remaining)
+                } else {
+                    (amount, amount * price)
+                }
+            }
+        };
+        if quantity <= Decimal::ZERO {
+            break;
+        }
+        filled_quantity += quantity;
+        filled_notional += notional;
+        worst_price = price;
+        match exchanges.iter_mut().find(|(e, _, _)| *e == level.exchange) {
+            Some((_, exchange_quantity, exchange_notional)) => {
+                *exchange_quantity += quantity;
+                *exchange_notional += notional;
+            }
+            None => exchanges.push((&level.exchange, quantity, notional)),
+        }
+    }
+    let filled_size = match target {
+        FillTarget::Quantity(_) => filled_quantity,
+        FillTarget::Notional(_) => filled_notional,
+    };
+    let best_price = levels.first().map_or(Decimal::ZERO, |best| {
+        parse_or(&best.price_decimal, best.price)
+    });
+    let price_scale = summary.price_scale;
+    let quantity_scale = summary.quantity_scale;
+    let notional_scale = price_scale + quantity_scale;
+    let mut estimate = FillEstimate {
+        best_price: to_f64(best_price),
+        worst_price: to_f64(worst_price),
+        filled_quantity: to_f64(filled_quantity),
+        filled_notional: to_f64(filled_notional),
+        exchanges: exchanges
+            .into_iter()
+            .map(|(exchange, quantity, notional)| ExchangeFill {
+                exchange: exchange.to_owned(),
+                quantity: to_f64(quantity),
+                notional: to_f64(notional),
+                quantity_decimal: format_scaled(quantity, quantity_scale),
+                notional_decimal: format_scaled(notional, notional_scale),
+            })
+            .collect(),
+        insufficient_depth: filled_size < target_size,
+        best_price_decimal: format_scaled(best_price, price_scale),
+        worst_price_decimal: format_scaled(worst_price, price_scale),
+        filled_quantity_decimal: format_scaled(filled_quantity, quantity_scale),
+        filled_notional_decimal: format_scaled(filled_notional, notional_scale),
+        ..Default::default()
+    };
+    if filled_quantity > Decimal::ZERO {
+        let vwap = filled_notional / filled_quantity;
+        let price_difference = match side {
+            Side::Buy => vwap - best_price,
+            Side::Sell => best_price - vwap,
+        };
+        estimate.vwap = to_f64(vwap);
+        estimate.vwap_decimal = format_scaled(vwap, price_scale);
+        // A zero best price leaves no meaningful slippage, it stays at 0.
+        if let Some(slippage) = price_difference
+            .checked_div(best_price)
+            .and_then(|ratio| ratio.checked_mul(Decimal::from(10_000)))
+        {
+            estimate.slippage_bps = to_f64(slippage);
+        }
+    }
+    estimate
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn level(exchange: &str, price: f64, amount: f64) -> orderbook::Level {
+        orderbook::Level {
+            exchange: exchange.to_owned(),
+            price,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn quantity(size: f64) -> FillTarget {
+        FillTarget::try_from(Some(Target::Quantity(size))).unwrap()
+    }
+
+    fn notional(size: f64) -> FillTarget {
+        FillTarget::try_from(Some(Target::Notional(size))).unwrap()
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            bids: vec![level("a", 99.0, 1.0), level("b", 98.0, 2.0)],
+            asks: vec![
+                level("b", 100.0, 1.0),
+                level("a", 101.0, 1.0),
+                level("b", 102.0, 2.0),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buy_quantity() {
+        let estimate = estimate_fill(&summary(), Side::Buy, quantity(3.0));
+        assert_eq!(3.0, estimate.filled_quantity);
+        assert_eq!(303.0, estimate.filled_notional);
+        assert_eq!(101.0, estimate.vwap);
+        assert_eq!(100.0, estimate.best_price);
+        assert_eq!(102.0, estimate.worst_price);
+        assert!((estimate.slippage_bps - 100.0).abs() < 1e-9);
+        assert!(!estimate.insufficient_depth);
+        assert_eq!(
+            vec![
+                ExchangeFill {
+                    exchange: "b".to_owned(),
+                    quantity: 2.0,
+                    notional: 202.0,
+                    quantity_decimal: "2".to_owned(),
+                    notional_decimal: "202".to_owned(),
+                },
+                ExchangeFill {
+                    exchange: "a".to_owned(),
+                    quantity: 1.0,
+                    notional: 101.0,
+                    quantity_decimal: "1".to_owned(),
+                    notional_decimal: "101".to_owned(),
+                },
+            ],
+            estimate.exchanges
+        );
+    }
+
+    #[test]
+    fn test_sell_notional_with_insufficient_depth() {
+        let estimate = estimate_fill(&summary(), Side::Sell, notional(500.0));
+        assert_eq!(3.0, estimate.filled_quantity);
+        assert_eq!(295.0, estimate.filled_notional);
+        assert_eq!(98.0, estimate.worst_price);
+        assert!(estimate.insufficient_depth);
+    }
+
+    #[test]
+    fn test_partial_level_notional() {
+        let estimate = estimate_fill(&summary(), Side::Buy, notional(150.5));
+        assert_eq!(1.5, estimate.filled_quantity);
+        assert_eq!(101.0, estimate.worst_price);
+        assert!(!estimate.insufficient_depth);
+    }
+
+    #[test]
+    fn test_exact_depth() {
+        let decimal_level = |exchange: &str, price: &str, amount: &str| orderbook::Level {
+            price_decimal: price.to_owned(),
+            amount_decimal: amount.to_owned(),
+            ..level(exchange, 0.0, 0.0)
+        };
+        let summary = Summary {
+            asks: vec![
+                decimal_level("a", "0.06520", "0.1"),
+                decimal_level("b", "0.06521", "0.2"),
+            ],
+            price_scale: 5,
+            quantity_scale: 1,
+            ..Default::default()
+        };
+        let estimate = estimate_fill(&summary, Side::Buy, quantity(0.3));
+        assert_eq!(0.3, estimate.filled_quantity);
+        assert_eq!("0.3", estimate.filled_quantity_decimal);
+        assert_eq!("0.019562", estimate.filled_notional_decimal);
+        assert_eq!("0.06521", estimate.worst_price_decimal);
+        assert!(!estimate.insufficient_depth);
+        let estimate = estimate_fill(&summary, Side::Buy, quantity(0.30000001));
+        assert!(estimate.insufficient_depth);
+    }
+
+    #[test]
+    fn test_invalid_target() {
+        for size in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert_eq!(
+                Err(FillError::InvalidTarget(size)).map_err(|e| e.to_string()),
+                FillTarget::try_from(Some(Target::Quantity(size))).map_err(|e| e.to_string())
+            );
+        }
+        assert_eq!(Err(FillError::MissingTarget), FillTarget::try_from(None));
+    }
+
+    #[test]
+    fn test_zero_best_price() {
+        let summary = Summary {
+            asks: vec![level("a", 0.0, 1.0), level("b", 1.0, 1.0)],
+            ..Default::default()
+        };
+        let estimate = estimate_fill(&summary, Side::Buy, quantity(2.0));
+        assert_eq!(0.5, estimate.vwap);
+        assert_eq!(0.0, estimate.slippage_bps);
+    }
+}
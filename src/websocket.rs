@@ -0,0 +1,269 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::orderbook::{self, Summary};
+use crate::server::{accept_error, conflation_interval, snapshot_stream};
+use crate::symbols::SymbolAliases;
+
+type SummaryStream = Pin<Box<dyn Stream<Item = Summary> + Send>>;
+
+/// Messages sent by the clients, as JSON text frames.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe(Subscription),
+    Unsubscribe,
+}
+
+/// Options of a subscription, mirroring the `BookSummary` request.
+#[derive(Debug, Deserialize, PartialEq, Default)]
+struct Subscription {
+    /// The aggregated symbol when not set.
+    symbol: Option<String>,
+    /// Levels sent on each side, all of them when not set.
+    depth: Option<usize>,
+    #[serde(default)]
+    min_interval_ms: u32,
+    #[serde(default)]
+    max_updates_per_second: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerMessage {
+    Subscribed { symbol: String },
+    Unsubscribed,
+    Summary(Box<Summary>),
+    Error { message: String },
+}
+
+/// Accepts WebSocket clients, streaming the summaries of `symbol` as JSON to the subscribed ones.
+/// Clients may subscribe with any of its aliases.
+pub async fn websocket_gateway(
+    listener: TcpListener,
+    summaries: watch::Receiver<Summary>,
+    symbol: SymbolAliases,
+) -> anyhow::Result<()> {
+    info!("WebSocket gateway listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_error(e).await;
+                continue;
+            }
+        };
+        let summaries = summaries.clone();
+        let symbol = symbol.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_client(stream, peer, summaries, &symbol).await {
+                warn!("WebSocket client {} error: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    summaries: watch::Receiver<Summary>,
+    symbol: &SymbolAliases,
+) -> anyhow::Result<()> {
+    let websocket = tokio_tungstenite::accept_async(stream).await?;
+    info!("WebSocket client {} connected", peer);
+    let (mut writer, mut reader) = websocket.split();
+    let mut updates: Option<SummaryStream> = None;
+    loop {
+        tokio::select! {
+            message = reader.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                match message? {
+                    Message::Text(text) => {
+                        let reply = handle_message(&text, &summaries, symbol, &mut updates);
+                        send(&mut writer, &reply).await?;
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            summary = next_summary(&mut updates) => {
+                let Some(summary) = summary else {
+                    info!("summaries channel closed. Disconnecting {}", peer);
+                    break;
+                };
+                send(&mut writer, &ServerMessage::Summary(Box::new(summary))).await?;
+            }
+        }
+    }
+    info!("WebSocket client {} disconnected", peer);
+    Ok(())
+}
+
+/// Waits forever without a subscription.
+async fn next_summary(updates: &mut Option<SummaryStream>) -> Option<Summary> {
+    match updates {
+        Some(updates) => updates.next().await,
+        None => future::pending().await,
+    }
+}
+
+async fn send<W>(writer: &mut W, message: &ServerMessage) -> anyhow::Result<()>
+where
+    W: Sink<Message> + Unpin,
+    W::Error: std::error::Error + Send + Sync + 'static,
+{
+    writer
+        .send(Message::Text(serde_json::to_string(message)?))
+        .await?;
+    Ok(())
+}
+
+/// Updates the subscription of a client, replacing any previous one.
+fn handle_message(
+    text: &str,
+    summaries: &watch::Receiver<Summary>,
+    symbol: &SymbolAliases,
+    updates: &mut Option<SummaryStream>,
+) -> ServerMessage {
+    let subscription = match serde_json::from_str(text) {
+        Ok(ClientMessage::Subscribe(subscription)) => subscription,
+        Ok(ClientMessage::Unsubscribe) => {
+            *updates = None;
+            return ServerMessage::Unsubscribed;
+        }
+        Err(e) => {
+            return ServerMessage::Error {
+                message: format!("invalid message: {e}"),
+            }
+        }
+    };
+    if let Some(requested) = &subscription.symbol {
+        if !symbol.matches(requested) {
+            return ServerMessage::Error {
+                message: format!(
+                    "unknown symbol {requested}, only {} is aggregated",
+                    symbol.symbol()
+                ),
+            };
+        }
+    }
+    let request = orderbook::BookSummaryRequest {
+        min_interval_ms: subscription.min_interval_ms,
+        max_updates_per_second: subscription.max_updates_per_second,
+        ..Default::default()
+    };
+    let min_interval = match conflation_interval(&request) {
+        Ok(min_interval) => min_interval,
+        Err(e) => {
+            return ServerMessage::Error {
+                message: e.to_string(),
+            }
+        }
+    };
+    let depth = subscription.depth;
+    *updates = Some(Box::pin(
+        snapshot_stream(summaries.clone(), min_interval).map(move |mut summary| {
+            if let Some(depth) = depth {
+                truncate(&mut summary, depth);
+            }
+            summary
+        }),
+    ));
+    ServerMessage::Subscribed {
+        symbol: symbol.symbol().to_owned(),
+    }
+}
+
+/// Keeps the best `depth` levels of every side.
+pub(crate) fn truncate(summary: &mut Summary, depth: usize) {
+    summary.bids_truncated |= summary.bids.len() > depth;
+    summary.asks_truncated |= summary.asks.len() > depth;
+    summary.bids.truncate(depth);
+    summary.asks.truncate(depth);
+    summary.consolidated_bids.truncate(depth);
+    summary.consolidated_asks.truncate(depth);
+    summary.grouped_bids.truncate(depth);
+    summary.grouped_asks.truncate(depth);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio_tungstenite::connect_async;
+
+    use crate::symbols::SymbolRules;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_client_message() {
+        assert_eq!(
+            ClientMessage::Subscribe(Subscription {
+                symbol: Some("ethbtc".to_owned()),
+                depth: Some(5),
+                ..Default::default()
+            }),
+            serde_json::from_str(r#"{"type": "subscribe", "symbol": "ethbtc", "depth": 5}"#)
+                .unwrap()
+        );
+        assert_eq!(
+            ClientMessage::Unsubscribe,
+            serde_json::from_str(r#"{"type": "unsubscribe"}"#).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let level = orderbook::Level {
+            exchange: "a".to_owned(),
+            price: 1.0,
+            ..Default::default()
+        };
+        let (_sender, summaries) = watch::channel(Summary {
+            bids: vec![level.clone(), level],
+            ..Default::default()
+        });
+        let symbol = SymbolAliases::new("ethbtc".to_owned(), vec![SymbolRules::concatenated()]);
+        tokio::spawn(websocket_gateway(listener, summaries, symbol));
+
+        let (mut websocket, _) = connect_async(format!("ws://{address}")).await.unwrap();
+        let request = |text: &str| Message::Text(text.to_owned());
+        websocket
+            .send(request(r#"{"type": "subscribe", "symbol": "ltcbtc"}"#))
+            .await
+            .unwrap();
+        websocket
+            .send(request(
+                r#"{"type": "subscribe", "symbol": "ETH/BTC", "depth": 1}"#,
+            ))
+            .await
+            .unwrap();
+        let mut replies = vec![];
+        for _ in 0..3 {
+            let reply = websocket
+                .next()
+                .await
+                .unwrap()
+                .unwrap()
+                .into_text()
+                .unwrap();
+            replies.push(serde_json::from_str::<serde_json::Value>(&reply).unwrap());
+        }
+        assert_eq!("error", replies[0]["type"]);
+        assert_eq!("subscribed", replies[1]["type"]);
+        assert_eq!("summary", replies[2]["type"]);
+        assert_eq!(1, replies[2]["bids"].as_array().unwrap().len());
+        assert_eq!(true, replies[2]["bids_truncated"]);
+    }
+}